@@ -0,0 +1,279 @@
+use crate::protocol::Topology;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// failed calls in a row after which a peer is left alone for BREAKER_COOLDOWN, then probed
+// with a single call
+const BREAKER_THRESHOLD: u32 = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(5);
+
+// every node this one knows of with that node's peers, as announced by init and topology
+// messages, and what this node tracks per peer. This node itself is never in it, so
+// neighbour loops don't have to skip it.
+#[derive(Default)]
+pub struct AddressBook {
+    peers: Mutex<HashMap<String, HashSet<String>>>,
+    // sorted keys of peers, rebuilt whenever they change so the broadcast path can read them
+    // without taking the peers lock
+    neighbours: RwLock<Arc<Vec<String>>>,
+    // values each peer last reported seeing, see `Handler::check_convergence`
+    counts: Mutex<HashMap<String, usize>>,
+    // when anti-entropy last completed a sync with each peer
+    last_sync: Mutex<HashMap<String, Instant>>,
+    // per-peer circuit breakers, calls to a peer that keeps failing are skipped for a while
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+impl AddressBook {
+    // a node known from init, it has no peers of its own until a topology names them
+    pub fn add_peer(&self, node_id: &str, peer: &str) {
+        if peer == node_id {
+            return;
+        }
+        let mut peers = self.peers.lock().unwrap();
+        peers.entry(peer.to_string()).or_default();
+        self.cache_neighbours(&peers);
+    }
+
+    // cheap to call on every message
+    pub fn neighbours(&self) -> Arc<Vec<String>> {
+        self.neighbours.read().unwrap().clone()
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.peers.lock().unwrap().contains_key(node)
+    }
+
+    // a node's peers are replaced so stale edges go away, nodes that aren't part of topology
+    // keep what they had. Returns the whole graph including node_id's own edges, which are
+    // only needed to build the spanning tree and aren't kept.
+    pub fn set_topology(
+        &self,
+        topology: Topology,
+        node_id: &str,
+    ) -> HashMap<String, HashSet<String>> {
+        let mut peers = self.peers.lock().unwrap();
+        for (node, node_peers) in topology {
+            peers.insert(node, node_peers.into_iter().collect());
+        }
+        let graph = peers.clone();
+        peers.remove(node_id);
+        self.cache_neighbours(&peers);
+        graph
+    }
+
+    // every known node with its peers
+    pub fn to_map(&self) -> HashMap<String, HashSet<String>> {
+        self.peers.lock().unwrap().clone()
+    }
+
+    pub fn set_count(&self, peer: &str, count: usize) {
+        self.counts.lock().unwrap().insert(peer.to_string(), count);
+    }
+
+    // a peer that didn't answer has no count, it can't be called converged
+    pub fn clear_count(&self, peer: &str) {
+        self.counts.lock().unwrap().remove(peer);
+    }
+
+    // true if every known peer last reported exactly `count` values
+    pub fn all_counts_equal(&self, count: usize) -> bool {
+        let peers = self.neighbours();
+        let counts = self.counts.lock().unwrap();
+        peers.iter().all(|peer| counts.get(peer) == Some(&count))
+    }
+
+    pub fn record_sync(&self, peer: &str) {
+        self.last_sync
+            .lock()
+            .unwrap()
+            .insert(peer.to_string(), Instant::now());
+    }
+
+    // the ones of peers synced with longest ago, all that were never synced if there are any
+    pub fn stalest_peers(&self, peers: &[String]) -> Vec<String> {
+        let last_sync = self.last_sync.lock().unwrap();
+        let Some(oldest) = peers.iter().map(|peer| last_sync.get(peer)).min() else {
+            return vec![];
+        };
+        peers
+            .iter()
+            .filter(|peer| last_sync.get(*peer) == oldest)
+            .cloned()
+            .collect()
+    }
+
+    pub fn breaker_allows(&self, peer: &str) -> bool {
+        let mut breakers = self.breakers.lock().unwrap();
+        breakers
+            .entry(peer.to_string())
+            .or_default()
+            .try_call(Instant::now())
+    }
+
+    pub fn breaker_is_open(&self, peer: &str) -> bool {
+        let breakers = self.breakers.lock().unwrap();
+        breakers
+            .get(peer)
+            .is_some_and(|breaker| breaker.is_open(Instant::now()))
+    }
+
+    pub fn record_call(&self, peer: &str, ok: bool) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(peer.to_string()).or_default();
+        if ok {
+            if breaker.succeeded() {
+                info!("{} answers again, resuming calls", peer);
+            }
+        } else if breaker.failed(Instant::now()) {
+            warn!(
+                "{} failed {} calls in a row, probing it every {:?} only",
+                peer, breaker.failures, BREAKER_COOLDOWN
+            );
+        }
+    }
+
+    // called with the peers lock held, so concurrent updates can't store a stale list
+    fn cache_neighbours(&self, peers: &HashMap<String, HashSet<String>>) {
+        let mut neighbours: Vec<String> = peers.keys().cloned().collect();
+        neighbours.sort();
+        *self.neighbours.write().unwrap() = Arc::new(neighbours);
+    }
+}
+
+// circuit breaker of one peer: closed while calls succeed, open after BREAKER_THRESHOLD
+// failures in a row. An open breaker lets one probe through per cooldown, its success closes it.
+#[derive(Default, Debug)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn is_open(&self, now: Instant) -> bool {
+        self.open_until.is_some_and(|until| now < until)
+    }
+
+    // true if a call may go out now. Past the cooldown the call is the probe, the next one
+    // waits for another cooldown unless the probe closes the breaker first.
+    fn try_call(&mut self, now: Instant) -> bool {
+        match self.open_until {
+            None => true,
+            Some(until) if now >= until => {
+                self.open_until = Some(now + BREAKER_COOLDOWN);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    // true if this closed an open breaker
+    fn succeeded(&mut self) -> bool {
+        self.failures = 0;
+        self.open_until.take().is_some()
+    }
+
+    // true if this opened the breaker
+    fn failed(&mut self, now: Instant) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures < BREAKER_THRESHOLD {
+            return false;
+        }
+        let opened = self.open_until.is_none();
+        self.open_until = Some(now + BREAKER_COOLDOWN);
+        opened
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology(edges: &[(&str, &[&str])]) -> Topology {
+        edges
+            .iter()
+            .map(|(node, peers)| {
+                let peers = peers.iter().map(|peer| peer.to_string()).collect();
+                (node.to_string(), peers)
+            })
+            .collect()
+    }
+
+    fn peers(peers: &[&str]) -> HashSet<String> {
+        peers.iter().map(|peer| peer.to_string()).collect()
+    }
+
+    #[test]
+    fn new_topology_replaces_peers_of_the_nodes_it_names() {
+        let book = AddressBook::default();
+        book.set_topology(topology(&[("n1", &["n2", "n3"]), ("n2", &["n1"])]), "n0");
+        book.set_topology(topology(&[("n1", &["n3"])]), "n0");
+
+        let map = book.to_map();
+        assert_eq!(map["n1"], peers(&["n3"]));
+        // not in the second topology, keeps what it had
+        assert_eq!(map["n2"], peers(&["n1"]));
+    }
+
+    #[test]
+    fn adding_the_node_itself_is_a_no_op() {
+        let book = AddressBook::default();
+        book.add_peer("n1", "n1");
+        assert!(!book.contains("n1"));
+        assert!(book.neighbours().is_empty());
+
+        book.add_peer("n1", "n2");
+        assert_eq!(*book.neighbours(), vec!["n2".to_string()]);
+    }
+
+    #[test]
+    fn stalest_peers_are_the_unsynced_then_the_oldest() {
+        let book = AddressBook::default();
+        let all: Vec<String> = ["n1", "n2", "n3"].map(String::from).to_vec();
+        assert_eq!(book.stalest_peers(&all), all);
+
+        book.record_sync("n2");
+        // so the sync times differ
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        book.record_sync("n1");
+        assert_eq!(book.stalest_peers(&all), vec!["n3"]);
+        book.record_sync("n3");
+        assert_eq!(book.stalest_peers(&all), vec!["n2"]);
+        assert!(book.stalest_peers(&[]).is_empty());
+    }
+
+    #[test]
+    fn failing_peer_opens_its_breaker_and_a_good_probe_closes_it() {
+        let start = Instant::now();
+        let mut breaker = Breaker::default();
+        for _ in 1..BREAKER_THRESHOLD {
+            assert!(!breaker.failed(start));
+        }
+        assert!(breaker.failed(start));
+        assert!(breaker.is_open(start));
+        assert!(!breaker.try_call(start));
+
+        // past the cooldown one probe goes out, the next call waits for it
+        let later = start + BREAKER_COOLDOWN;
+        assert!(!breaker.is_open(later));
+        assert!(breaker.try_call(later));
+        assert!(!breaker.try_call(later));
+        assert!(breaker.succeeded());
+        assert!(!breaker.is_open(later));
+        assert!(breaker.try_call(later));
+    }
+
+    #[test]
+    fn addressbook_skips_a_failing_peer() {
+        let book = AddressBook::default();
+        for _ in 0..BREAKER_THRESHOLD {
+            assert!(book.breaker_allows("n2"));
+            book.record_call("n2", false);
+        }
+        assert!(book.breaker_is_open("n2"));
+        assert!(!book.breaker_allows("n2"));
+        assert!(book.breaker_allows("n3"));
+    }
+}
@@ -0,0 +1,260 @@
+use crate::db::Durability;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::warn;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+// relays a client's broadcast may take before nodes stop forwarding it, anti-entropy covers
+// whatever is further away. 32 is more than the diameter of any maelstrom topology.
+const MAX_HOPS: u8 = 32;
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(500);
+const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_millis(1000);
+const SENDERS: usize = 32;
+const DB_WRITE_RETRIES: u32 = 3;
+const DB_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BATCH_SIZE: usize = 100;
+const MAX_BATCH_DELAY: Duration = Duration::from_millis(200);
+const COMPACT_IDLE: Duration = Duration::from_secs(10);
+
+const WORKLOAD_ENV: &str = "GOSSIP_WORKLOAD";
+const ID_SCHEME_ENV: &str = "GOSSIP_ID_SCHEME";
+const FANOUT_ENV: &str = "GOSSIP_FANOUT";
+const RETRY_INTERVAL_ENV: &str = "GOSSIP_RETRY_INTERVAL_MS";
+const GOSSIP_INTERVAL_ENV: &str = "GOSSIP_INTERVAL_MS";
+const ANTI_ENTROPY_INTERVAL_ENV: &str = "GOSSIP_ANTI_ENTROPY_INTERVAL_MS";
+const MAX_HOPS_ENV: &str = "GOSSIP_MAX_HOPS";
+const SENDERS_ENV: &str = "GOSSIP_SENDERS";
+const SPANNING_TREE_ENV: &str = "GOSSIP_SPANNING_TREE";
+const DB_DIR_ENV: &str = "GOSSIP_DB_DIR";
+const KEEP_CORRUPT_DB_ENV: &str = "GOSSIP_KEEP_CORRUPT_DB";
+const STRICT_ENV: &str = "GOSSIP_STRICT";
+const DRY_RUN_ENV: &str = "GOSSIP_DRY_RUN";
+const DURABILITY_ENV: &str = "GOSSIP_DURABILITY";
+const OPTIMISTIC_ACKS_ENV: &str = "GOSSIP_OPTIMISTIC_ACKS";
+const SEED_ENV: &str = "GOSSIP_SEED";
+const MAX_BATCH_SIZE_ENV: &str = "GOSSIP_MAX_BATCH_SIZE";
+const MAX_BATCH_DELAY_ENV: &str = "GOSSIP_MAX_BATCH_DELAY_MS";
+const DB_WRITE_RETRIES_ENV: &str = "GOSSIP_DB_WRITE_RETRIES";
+const DB_TIMEOUT_ENV: &str = "GOSSIP_DB_TIMEOUT_MS";
+const COMPACT_IDLE_ENV: &str = "GOSSIP_COMPACT_IDLE_MS";
+const CONVERGENCE_WINDOW_ENV: &str = "GOSSIP_CONVERGENCE_WINDOW_MS";
+const DB_CACHE_SIZE_ENV: &str = "GOSSIP_DB_CACHE_SIZE";
+const NODE_ID_ENV: &str = "GOSSIP_NODE_ID";
+const MERKLE_SYNC_ENV: &str = "GOSSIP_MERKLE_SYNC";
+
+// which challenge the node serves, picked by GOSSIP_WORKLOAD
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub enum WorkloadKind {
+    #[default]
+    Broadcast,
+    GCounter,
+    PnCounter,
+    Kafka,
+    Txn,
+    GSet,
+    LinKv,
+}
+
+impl WorkloadKind {
+    fn from_env() -> Self {
+        match std::env::var(WORKLOAD_ENV).as_deref() {
+            Ok("g-counter") => WorkloadKind::GCounter,
+            Ok("pn-counter") => WorkloadKind::PnCounter,
+            Ok("kafka") => WorkloadKind::Kafka,
+            Ok("txn-rw-register") => WorkloadKind::Txn,
+            Ok("g-set") => WorkloadKind::GSet,
+            Ok("lin-kv") => WorkloadKind::LinKv,
+            _ => WorkloadKind::Broadcast,
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub enum IdScheme {
+    #[default]
+    Uuid,
+    // "<node_id>-<counter>", unique across the cluster since node ids are
+    NodeCounter,
+    // snowflake-style u64, roughly ordered by the time it was generated
+    Snowflake,
+}
+
+impl IdScheme {
+    fn from_env() -> Self {
+        match std::env::var(ID_SCHEME_ENV).as_deref() {
+            Ok("node-counter") => IdScheme::NodeCounter,
+            Ok("snowflake") => IdScheme::Snowflake,
+            _ => IdScheme::Uuid,
+        }
+    }
+}
+
+// every tunable of a node. The defaults are what a node runs with when nothing is set.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub workload: WorkloadKind,
+    pub id_scheme: IdScheme,
+    // how many randomly picked neighbours each new broadcast value is gossiped to
+    pub fanout: usize,
+    // first retry of an unacked value, later ones back off from here
+    pub retry_interval: Duration,
+    // how often counters and g-set elements are pushed to the neighbours
+    pub gossip_interval: Duration,
+    // how often a node syncs with the peer it synced with longest ago, filling gaps left by
+    // dropped broadcasts
+    pub anti_entropy_interval: Duration,
+    // anti-entropy compares merkle summaries and only sends values from leaves that differ,
+    // instead of shipping the whole set every round. A few more round trips, far fewer bytes
+    // between peers that are mostly in sync.
+    pub merkle_sync: bool,
+    // relays a client broadcast starts with
+    pub max_hops: u8,
+    // forward broadcasts only along the spanning tree edges instead of flooding every node
+    pub spanning_tree: bool,
+    // newly seen values are buffered per neighbour and sent as one batch once this many are
+    // queued for it, or max_batch_delay after the last flush, whichever comes first
+    pub max_batch_size: usize,
+    pub max_batch_delay: Duration,
+    // tasks sending broadcast batches, i.e. how many can be in flight at once
+    pub senders: usize,
+    // directory the node's db file goes in, the working directory if unset
+    pub db_dir: Option<PathBuf>,
+    // redb page cache in bytes, redb's default if unset, see `Db::open_with_cache`
+    pub db_cache_size: Option<usize>,
+    // recreate a corrupt db file on init instead of failing it, the node then refuses
+    // node-counter ids as it lost track of the ones it handed out
+    pub recover_db: bool,
+    // retries of a broadcast value write that failed with a transient error, e.g. a busy disk
+    pub db_write_retries: u32,
+    // longest a db read or write may take before the request fails with a timeout, which
+    // leaves open whether it happened. See `Db::with_timeout`
+    pub db_timeout: Duration,
+    // how broadcast value writes are committed, see `Db::with_durability`
+    pub durability: Durability,
+    // the db file is compacted once nothing was written to it for this long, compaction
+    // blocks every db call while it runs so it's kept out of busy stretches
+    pub compact_idle: Duration,
+    // ack a client broadcast once its write is queued instead of committed. Reads see the
+    // value right away since they're served from memory, but a crash before the commit loses
+    // it on this node: after a restart it is only back once a peer it was forwarded to
+    // syncs it over anti-entropy, and if it wasn't forwarded yet it's gone.
+    pub optimistic_acks: bool,
+    // seeds the node's random choices (fanout picks, anti-entropy ties, retry jitter) together
+    // with its node id, so GOSSIP_SEED=<u64> on every node makes those choices the same on the
+    // next run. Unset, the node id alone is the seed. Uuids are always truly random, seeded
+    // ones would repeat after a restart.
+    pub seed: Option<u64>,
+    // test aid: when set the node asks its neighbours for their value counts with a health
+    // request now and then, and logs once they all report as many as it has for this long.
+    // Off by default, every check is a round of rpcs.
+    pub convergence_window: Option<Duration>,
+    // node id to open the db under when running without maelstrom, where no init ever comes.
+    // Ignored once the runtime knows its id from an init.
+    pub node_id: Option<String>,
+    // reject requests carrying fields the protocol doesn't know instead of just logging them
+    pub strict: bool,
+    // log the gossip a node would send instead of sending it, for looking at forwarding
+    // decisions in a single node. Values are still stored and served.
+    pub dry_run: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            workload: WorkloadKind::default(),
+            id_scheme: IdScheme::default(),
+            fanout: usize::MAX,
+            retry_interval: RETRY_INTERVAL,
+            gossip_interval: GOSSIP_INTERVAL,
+            anti_entropy_interval: ANTI_ENTROPY_INTERVAL,
+            merkle_sync: false,
+            max_hops: MAX_HOPS,
+            spanning_tree: false,
+            max_batch_size: MAX_BATCH_SIZE,
+            max_batch_delay: MAX_BATCH_DELAY,
+            senders: SENDERS,
+            db_dir: None,
+            db_cache_size: None,
+            recover_db: true,
+            db_write_retries: DB_WRITE_RETRIES,
+            db_timeout: DB_TIMEOUT,
+            durability: Durability::Immediate,
+            compact_idle: COMPACT_IDLE,
+            optimistic_acks: false,
+            seed: None,
+            convergence_window: None,
+            node_id: None,
+            strict: false,
+            dry_run: false,
+        }
+    }
+}
+
+impl Config {
+    // reads the GOSSIP_* environment variables, anything unset or unparseable keeps its default
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            workload: WorkloadKind::from_env(),
+            id_scheme: IdScheme::from_env(),
+            fanout: env_parse(FANOUT_ENV).unwrap_or(default.fanout),
+            retry_interval: env_millis(RETRY_INTERVAL_ENV).unwrap_or(default.retry_interval),
+            gossip_interval: env_millis(GOSSIP_INTERVAL_ENV).unwrap_or(default.gossip_interval),
+            anti_entropy_interval: env_millis(ANTI_ENTROPY_INTERVAL_ENV)
+                .unwrap_or(default.anti_entropy_interval),
+            merkle_sync: std::env::var_os(MERKLE_SYNC_ENV).is_some(),
+            max_hops: env_parse(MAX_HOPS_ENV).unwrap_or(default.max_hops),
+            spanning_tree: std::env::var_os(SPANNING_TREE_ENV).is_some(),
+            max_batch_size: env_parse(MAX_BATCH_SIZE_ENV)
+                .unwrap_or(default.max_batch_size)
+                .max(1),
+            max_batch_delay: env_millis(MAX_BATCH_DELAY_ENV)
+                .unwrap_or(default.max_batch_delay)
+                .max(Duration::from_millis(1)),
+            senders: env_parse(SENDERS_ENV).unwrap_or(default.senders).max(1),
+            db_dir: std::env::var_os(DB_DIR_ENV).map(PathBuf::from),
+            db_cache_size: env_parse(DB_CACHE_SIZE_ENV),
+            recover_db: std::env::var_os(KEEP_CORRUPT_DB_ENV).is_none(),
+            db_write_retries: env_parse(DB_WRITE_RETRIES_ENV).unwrap_or(default.db_write_retries),
+            db_timeout: env_millis(DB_TIMEOUT_ENV)
+                .unwrap_or(default.db_timeout)
+                .max(Duration::from_millis(1)),
+            durability: durability_from_env().unwrap_or(default.durability),
+            compact_idle: env_millis(COMPACT_IDLE_ENV).unwrap_or(default.compact_idle),
+            optimistic_acks: std::env::var_os(OPTIMISTIC_ACKS_ENV).is_some(),
+            seed: env_parse(SEED_ENV),
+            convergence_window: env_millis(CONVERGENCE_WINDOW_ENV),
+            node_id: std::env::var(NODE_ID_ENV).ok().filter(|id| !id.is_empty()),
+            strict: std::env::var_os(STRICT_ENV).is_some(),
+            dry_run: std::env::var_os(DRY_RUN_ENV).is_some(),
+        }
+    }
+}
+
+fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("Ignoring {}={:?}, it doesn't parse", name, value);
+            None
+        }
+    }
+}
+
+fn durability_from_env() -> Option<Durability> {
+    match std::env::var(DURABILITY_ENV).ok()?.as_str() {
+        "none" => Some(Durability::None),
+        "eventual" => Some(Durability::Eventual),
+        "immediate" => Some(Durability::Immediate),
+        other => {
+            warn!("Ignoring {}={:?}, it doesn't parse", DURABILITY_ENV, other);
+            None
+        }
+    }
+}
+
+fn env_millis(name: &str) -> Option<Duration> {
+    env_parse(name).map(Duration::from_millis)
+}
@@ -0,0 +1,187 @@
+use std::collections::{BTreeSet, HashMap};
+
+// replicated state that converges by exchanging it: merge is commutative, associative and
+// idempotent, so nodes that merged each other's states in any order read the same value
+pub trait Crdt {
+    type Value;
+
+    fn merge(&mut self, other: &Self);
+    fn value(&self) -> Self::Value;
+}
+
+// grow-only counter kept as per-node sums, a node only ever adds to its own
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GCounter(HashMap<String, u64>);
+
+impl GCounter {
+    pub fn increment(&mut self, node_id: &str, n: u64) {
+        *self.0.entry(node_id.to_string()).or_default() += n;
+    }
+
+    pub fn sums(&self) -> &HashMap<String, u64> {
+        &self.0
+    }
+
+    pub fn into_sums(self) -> HashMap<String, u64> {
+        self.0
+    }
+}
+
+impl From<HashMap<String, u64>> for GCounter {
+    fn from(sums: HashMap<String, u64>) -> Self {
+        Self(sums)
+    }
+}
+
+impl Crdt for GCounter {
+    type Value = u64;
+
+    // sums only grow, the larger one of a node is the more recent
+    fn merge(&mut self, other: &Self) {
+        for (node_id, n) in &other.0 {
+            let sum = self.0.entry(node_id.clone()).or_default();
+            *sum = (*sum).max(*n);
+        }
+    }
+
+    fn value(&self) -> u64 {
+        self.0.values().sum()
+    }
+}
+
+// counter that also goes down, increments and decrements are two grow-only counters
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PNCounter {
+    pub increments: GCounter,
+    pub decrements: GCounter,
+}
+
+impl PNCounter {
+    pub fn add(&mut self, node_id: &str, delta: i64) {
+        if delta < 0 {
+            self.decrements.increment(node_id, delta.unsigned_abs());
+        } else {
+            self.increments.increment(node_id, delta.unsigned_abs());
+        }
+    }
+}
+
+impl Crdt for PNCounter {
+    type Value = i64;
+
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+
+    fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+// grow-only set, merging is the union
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GSet(BTreeSet<String>);
+
+impl GSet {
+    // returns true if the element wasn't in the set yet
+    pub fn insert(&mut self, element: String) -> bool {
+        self.0.insert(element)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn into_elements(self) -> Vec<String> {
+        self.0.into_iter().collect()
+    }
+}
+
+impl FromIterator<String> for GSet {
+    fn from_iter<I: IntoIterator<Item = String>>(elements: I) -> Self {
+        Self(elements.into_iter().collect())
+    }
+}
+
+impl Crdt for GSet {
+    // sorted
+    type Value = Vec<String>;
+
+    fn merge(&mut self, other: &Self) {
+        self.0.extend(other.0.iter().cloned());
+    }
+
+    fn value(&self) -> Vec<String> {
+        self.0.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pn_merge_in_either_order_gives_the_same_total() {
+        let mut a = PNCounter::default();
+        a.add("n1", 5);
+        a.add("n1", -2);
+        let mut b = PNCounter::default();
+        b.add("n2", 3);
+        b.add("n2", -4);
+        b.add("n1", 1);
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), 5 - 2 + 3 - 4);
+    }
+
+    #[test]
+    fn disjoint_g_sets_converge_to_their_union() {
+        let mut a: GSet = ["x", "z"].map(String::from).into_iter().collect();
+        let mut b: GSet = ["y"].map(String::from).into_iter().collect();
+        let (a_state, b_state) = (a.clone(), b.clone());
+        a.merge(&b_state);
+        b.merge(&a_state);
+        assert_eq!(a, b);
+        assert_eq!(a.value(), vec!["x", "y", "z"]);
+    }
+
+    // a merged with b, and b merged with a, must be the same state
+    fn assert_commutes<C: Crdt + Clone + PartialEq + std::fmt::Debug>(a: &C, b: &C) {
+        let mut ab = a.clone();
+        ab.merge(b);
+        let mut ba = b.clone();
+        ba.merge(a);
+        assert_eq!(ab, ba);
+        // merging again changes nothing
+        let mut again = ab.clone();
+        again.merge(b);
+        assert_eq!(again, ab);
+    }
+
+    #[test]
+    fn merges_commute() {
+        let mut a = GCounter::default();
+        a.increment("n1", 3);
+        a.increment("n2", 1);
+        let mut b = GCounter::default();
+        b.increment("n2", 4);
+        b.increment("n3", 2);
+        assert_commutes(&a, &b);
+
+        let mut a = PNCounter::default();
+        a.add("n1", -3);
+        let mut b = PNCounter::default();
+        b.add("n1", 2);
+        b.add("n2", -1);
+        assert_commutes(&a, &b);
+
+        let a: GSet = ["x", "y"].map(String::from).into_iter().collect();
+        let b: GSet = ["y", "z"].map(String::from).into_iter().collect();
+        assert_commutes(&a, &b);
+    }
+}
@@ -1,61 +1,1583 @@
-use redb::{Database, ReadableTable, TableDefinition, TableError};
-use std::sync::Arc;
+use crate::crdt::{Crdt, GCounter, GSet, PNCounter};
+pub use crate::stats::{DbStats, OpStats};
+use redb::backends::InMemoryBackend;
+pub use redb::Durability;
+use redb::{
+    CommitError, CompactionError, Database, DatabaseError, Key, ReadTransaction, ReadableTable,
+    ReadableTableMetadata, StorageError, TableDefinition, TableError, TransactionError,
+};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-const TABLE: TableDefinition<u64, bool> = TableDefinition::new("broadcast");
+const DEFAULT_TABLE: &str = "broadcast";
+// retries of a broadcast value write failing with a retriable error, unless configured
+const WRITE_RETRIES: u32 = 3;
+// first pause before retrying a write, doubled on every attempt
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(10);
+// how long a read or write may take before its caller gets `DbError::Timeout`, unless configured
+const TIMEOUT: Duration = Duration::from_secs(5);
+// g-counter: per-node grow-only sums, the counter value is their total
+const COUNTER: TableDefinition<&str, u64> = TableDefinition::new("counter");
+// pn-counter: per-node grow-only sums of the negative deltas
+const COUNTER_NEG: TableDefinition<&str, u64> = TableDefinition::new("counter_neg");
+// key -> stored payload, key/value counterpart of the broadcast set
+const VALUES: TableDefinition<u64, u64> = TableDefinition::new("values");
+// node id -> its peers as a JSON list, so a restarted node knows the topology again
+const TOPOLOGY: TableDefinition<&str, &str> = TableDefinition::new("topology");
+// txn-rw-register: key -> last written value
+const REGISTERS: TableDefinition<u64, u64> = TableDefinition::new("registers");
+// g-set: the elements themselves are the keys
+const VALUES_STR: TableDefinition<&str, ()> = TableDefinition::new("values_str");
+// broadcast values that aren't u64: hash of the JSON -> the JSON, see `Db::set_broadcast_blob`
+const BLOBS: TableDefinition<u64, &[u8]> = TableDefinition::new("broadcast_blobs");
+// lin-kv: key -> current value
+const KV: TableDefinition<u64, u64> = TableDefinition::new("kv");
+// node bookkeeping that has to survive restarts, see the keys below
+const META: TableDefinition<&str, u64> = TableDefinition::new("meta");
+// ids below this may have been handed out by the node-counter id scheme
+const ID_LIMIT: &str = "id_limit";
+
+pub enum TxnOp {
+    // key and the value read, filled in by `Db::apply_txn`
+    Read(u64, Option<u64>),
+    Write(u64, u64),
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    // opening, transaction, storage, commit and compaction failures, redb's errors are big so
+    // they're boxed to keep every Result small
+    Redb(Box<redb::Error>),
+    // kept apart from `Redb` so callers can tell a missing table from a broken disk
+    Table(Box<TableError>),
+    // the blocking task running the read panicked or was cancelled
+    Join(tokio::task::JoinError),
+    // the writer thread is gone, no more writes can happen
+    WriterStopped,
+    // an operation didn't finish within the db's timeout, e.g. on a stalled disk. It keeps
+    // running in the background and may still commit.
+    Timeout(Duration),
+    // a stored value that doesn't decode, e.g. a topology entry
+    Json(serde_json::Error),
+    Io(std::io::Error),
+    // commit of an offset the log for key hasn't reached yet
+    OffsetNotSent { key: String, offset: u64 },
+    // cas on a key that was never written
+    KeyNotFound(u64),
+    // cas whose from doesn't match the current value
+    CasMismatch { key: u64, from: u64, current: u64 },
+    // an add that would take a node's sum past u64::MAX
+    CounterOverflow { node_id: String },
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Redb(e) => write!(f, "{}", e),
+            DbError::Table(e) => write!(f, "{}", e),
+            DbError::Join(e) => write!(f, "db task failed: {}", e),
+            DbError::WriterStopped => write!(f, "db writer has stopped"),
+            DbError::Timeout(timeout) => write!(f, "db operation timed out after {:?}", timeout),
+            DbError::Json(e) => write!(f, "undecodable stored value: {}", e),
+            DbError::Io(e) => write!(f, "{}", e),
+            DbError::OffsetNotSent { key, offset } => {
+                write!(f, "offset {} was never sent for key {}", offset, key)
+            }
+            DbError::KeyNotFound(key) => write!(f, "key {} does not exist", key),
+            DbError::CasMismatch { key, from, current } => {
+                write!(f, "key {} holds {}, not {}", key, current, from)
+            }
+            DbError::CounterOverflow { node_id } => {
+                write!(f, "counter of {} would overflow", node_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Redb(e) => Some(e.as_ref()),
+            DbError::Table(e) => Some(e.as_ref()),
+            DbError::Join(e) => Some(e),
+            DbError::WriterStopped
+            | DbError::Timeout(_)
+            | DbError::OffsetNotSent { .. }
+            | DbError::KeyNotFound(_)
+            | DbError::CasMismatch { .. }
+            | DbError::CounterOverflow { .. } => None,
+            DbError::Json(e) => Some(e),
+            DbError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl DbError {
+    // failures that may well not happen again, e.g. an i/o error while the disk is busy.
+    // After a failed i/o redb reports PreviousIo until reopened, that one is fatal.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            DbError::Redb(e) => match e.as_ref() {
+                redb::Error::Io(e) => e.kind() != std::io::ErrorKind::InvalidData,
+                redb::Error::TransactionInProgress => true,
+                _ => false,
+            },
+            DbError::Io(e) => e.kind() != std::io::ErrorKind::InvalidData,
+            _ => false,
+        }
+    }
+}
+
+impl From<TableError> for DbError {
+    fn from(e: TableError) -> Self {
+        DbError::Table(Box::new(e))
+    }
+}
+
+impl From<tokio::task::JoinError> for DbError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        DbError::Join(e)
+    }
+}
+
+impl From<serde_json::Error> for DbError {
+    fn from(e: serde_json::Error) -> Self {
+        DbError::Json(e)
+    }
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(e: std::io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
+
+macro_rules! from_redb_error {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for DbError {
+                fn from(e: $error) -> Self {
+                    DbError::Redb(Box::new(e.into()))
+                }
+            }
+        )*
+    };
+}
+
+from_redb_error!(
+    redb::Error,
+    DatabaseError,
+    TransactionError,
+    StorageError,
+    CommitError,
+    CompactionError
+);
+
+// a write run on the writer thread, it sends its own result back
+type WriteJob = Box<dyn FnOnce(&Database) + Send>;
 
 pub struct Db {
-    db: Arc<Database>,
+    // transactions hold the read lock, compaction takes the write lock to get the database to itself
+    pub(crate) db: Arc<RwLock<Database>>,
+    // backing file, none for in-memory databases
+    path: Option<PathBuf>,
+    // all writes go through one dedicated thread, redb runs write transactions one at a time
+    // anyway and a flood of writes can't exhaust tokio's blocking pool this way
+    writer: mpsc::Sender<WriteJob>,
+    // name of the table holding broadcast values, lets one database host several independent sets
+    table: String,
+    // applied to the broadcast value writes, the other tables always commit immediately
+    durability: Durability,
+    // see `with_write_retries`
+    write_retries: u32,
+    // see `with_timeout`
+    timeout: Duration,
+    // when a write was last queued, see `last_write`
+    last_write: Mutex<Instant>,
+    // see `recovered`
+    recovered: bool,
+    stats: Arc<Mutex<DbStats>>,
+}
+
+// runs write jobs in order until every sender, i.e. the Db, is dropped
+fn spawn_writer(db: Arc<RwLock<Database>>) -> mpsc::Sender<WriteJob> {
+    let (tx, rx) = mpsc::channel::<WriteJob>();
+    std::thread::spawn(move || {
+        for job in rx {
+            let db = db.read().unwrap();
+            job(&db);
+        }
+    });
+    tx
+}
+
+// a file redb doesn't recognise fails the magic number check with InvalidData
+fn is_corrupt(e: &StorageError) -> bool {
+    match e {
+        StorageError::Corrupted(_) => true,
+        StorageError::Io(e) => e.kind() == std::io::ErrorKind::InvalidData,
+        _ => false,
+    }
+}
+
+// every entry of def mapped through `entry`, in key order. Missing tables come back empty.
+pub(crate) fn dump_table<K, V, T>(
+    read_txn: &ReadTransaction,
+    def: TableDefinition<K, V>,
+    entry: impl for<'a> Fn(K::SelfType<'a>, V::SelfType<'a>) -> Result<T, DbError>,
+) -> Result<Vec<T>, DbError>
+where
+    K: Key + 'static,
+    V: redb::Value + 'static,
+{
+    let mut entries = vec![];
+    let table = match read_txn.open_table(def) {
+        Ok(table) => table,
+        Err(TableError::TableDoesNotExist(_)) => return Ok(entries),
+        Err(e) => return Err(e.into()),
+    };
+    for res in table.iter()? {
+        let (k, v) = res?;
+        entries.push(entry(k.value(), v.value())?);
+    }
+    Ok(entries)
+}
+
+fn counter_table(
+    read_txn: &ReadTransaction,
+    definition: TableDefinition<&str, u64>,
+) -> Result<GCounter, DbError> {
+    let sums = dump_table(read_txn, definition, |node_id, sum| {
+        Ok((node_id.to_string(), sum))
+    })?;
+    Ok(sums.into_iter().collect::<HashMap<_, _>>().into())
+}
+
+// 64-bit FNV-1a. Its output is fixed by the algorithm, unlike std's DefaultHasher which may
+// change between Rust releases, so a db reopened by another build finds its blobs' keys.
+fn blob_key(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn broadcast_table(name: &str) -> TableDefinition<'_, u64, bool> {
+    TableDefinition::new(name)
+}
+
+// one write transaction storing bytes under key, true if they were not stored before. A
+// different value already under key is a hash collision, the bytes go to the next free key
+// after it then.
+fn insert_blob(db: &Database, mut key: u64, bytes: &[u8]) -> Result<bool, DbError> {
+    let write_txn = db.begin_write()?;
+    {
+        let mut table = write_txn.open_table(BLOBS)?;
+        loop {
+            match table.get(key)? {
+                None => break,
+                Some(stored) if stored.value() == bytes => return Ok(false),
+                Some(_) => {
+                    tracing::warn!("Blob hash collision on key {}, probing the next one", key);
+                    key = key.wrapping_add(1);
+                }
+            }
+        }
+        table.insert(key, bytes)?;
+    }
+    write_txn.commit()?;
+
+    Ok(true)
+}
+
+// one write transaction storing id, true if it was not stored before
+fn insert_broadcast_id(
+    db: &Database,
+    table_name: &str,
+    durability: Durability,
+    id: u64,
+) -> Result<bool, DbError> {
+    let mut write_txn = db.begin_write()?;
+    write_txn.set_durability(durability);
+    let inserted = {
+        let mut table = write_txn.open_table(broadcast_table(table_name))?;
+        let previous = table.insert(id, true)?;
+        previous.is_none()
+    };
+    write_txn.commit()?;
+
+    Ok(inserted)
 }
 
 impl Db {
-    pub fn new(filename: &str) -> Result<Self, String> {
-        let db = Database::create(format!("{}.redb", filename)).map_err(|e| e.to_string())?;
-        Ok(Self { db: Arc::new(db) })
+    // opens `<filename>.redb` in the working directory
+    pub fn new(filename: &str) -> Result<Self, DbError> {
+        Self::new_at(format!("{}.redb", filename))
+    }
+
+    // opens `<dir>/<filename>.redb`
+    pub fn new_in(dir: impl AsRef<Path>, filename: &str) -> Result<Self, DbError> {
+        Self::new_at(dir.as_ref().join(format!("{}.redb", filename)))
+    }
+
+    // a corrupt file fails the open and is left alone, see `open` for recovering it
+    pub fn new_at(path: impl AsRef<Path>) -> Result<Self, DbError> {
+        Self::open(path, false)
+    }
+
+    // missing parent directories are created. With `recover` a corrupt file, e.g. left by a
+    // crash mid-write, is deleted and started over instead of failing the open.
+    pub fn open(path: impl AsRef<Path>, recover: bool) -> Result<Self, DbError> {
+        Self::open_with_cache(path, recover, None)
+    }
+
+    // `open` with redb's page cache capped at cache_size bytes instead of its default. A bigger
+    // cache keeps more of a large broadcast table in memory, so reads and the page lookups of
+    // writes skip the disk, at the cost of that much more resident memory per node. A small one
+    // keeps nodes lean but every miss is a read from the file.
+    pub fn open_with_cache(
+        path: impl AsRef<Path>,
+        recover: bool,
+        cache_size: Option<usize>,
+    ) -> Result<Self, DbError> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let create = || {
+            let mut builder = Database::builder();
+            if let Some(cache_size) = cache_size {
+                builder.set_cache_size(cache_size);
+            }
+            builder.create(path)
+        };
+        let mut recovered = false;
+        let db = match create() {
+            Err(DatabaseError::Storage(e)) if recover && is_corrupt(&e) => {
+                tracing::warn!("Db {} is corrupt ({}), recreating it", path.display(), e);
+                std::fs::remove_file(path)?;
+                recovered = true;
+                create()?
+            }
+            db => db?,
+        };
+        let db = Arc::new(RwLock::new(db));
+        Ok(Self {
+            writer: spawn_writer(db.clone()),
+            db,
+            path: Some(path.to_path_buf()),
+            table: DEFAULT_TABLE.to_string(),
+            durability: Durability::Immediate,
+            write_retries: WRITE_RETRIES,
+            timeout: TIMEOUT,
+            last_write: Mutex::new(Instant::now()),
+            recovered,
+            stats: Arc::default(),
+        })
+    }
+
+    // nothing touches the filesystem, handy for tests
+    pub fn new_in_memory() -> Result<Self, DbError> {
+        let db = Arc::new(RwLock::new(
+            Database::builder().create_with_backend(InMemoryBackend::new())?,
+        ));
+        Ok(Self {
+            writer: spawn_writer(db.clone()),
+            db,
+            path: None,
+            table: DEFAULT_TABLE.to_string(),
+            durability: Durability::Immediate,
+            write_retries: WRITE_RETRIES,
+            timeout: TIMEOUT,
+            last_write: Mutex::new(Instant::now()),
+            recovered: false,
+            stats: Arc::default(),
+        })
+    }
+
+    #[must_use]
+    pub fn with_table_name(mut self, name: impl Into<String>) -> Self {
+        self.table = name.into();
+        self
+    }
+
+    // Durability::None trades safety for throughput: those commits aren't on disk until a later
+    // durable one (see `flush`), so a crash loses every value stored since. It also keeps
+    // redb from freeing pages, the file grows until then.
+    #[must_use]
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    // how often a broadcast value write failing with a retriable error is tried again, with
+    // doubling pauses in between, before its error is returned
+    #[must_use]
+    pub fn with_write_retries(mut self, retries: u32) -> Self {
+        self.write_retries = retries;
+        self
+    }
+
+    // upper bound on every read and write, a hung transaction fails with `DbError::Timeout`
+    // after it instead of blocking its caller forever. Waiting for a busy writer counts too.
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // true if the file was corrupt and `open` started it over, everything stored before is
+    // gone, including what's in META
+    pub fn recovered(&self) -> bool {
+        self.recovered
+    }
+
+    pub fn stats(&self) -> DbStats {
+        *self.stats.lock().unwrap()
+    }
+
+    // queues f on the writer thread and waits for its result
+    pub(crate) async fn write<T, F>(&self, f: F) -> Result<T, DbError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T, DbError> + Send + 'static,
+    {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        *self.last_write.lock().unwrap() = Instant::now();
+        // the writer thread runs it inside the caller's span, e.g. the request it's for
+        let span = tracing::debug_span!("db_write");
+        self.writer
+            .send(Box::new(move |db: &Database| {
+                let _span = span.enter();
+                let _ = tx.send(f(db));
+            }))
+            .map_err(|_| DbError::WriterStopped)?;
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(result) => result.map_err(|_| DbError::WriterStopped)?,
+            Err(_) => Err(DbError::Timeout(self.timeout)),
+        }
+    }
+
+    // runs f on tokio's blocking pool, for reads and compaction
+    pub(crate) async fn blocking<T, F>(&self, f: F) -> Result<T, DbError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, DbError> + Send + 'static,
+    {
+        match tokio::time::timeout(self.timeout, tokio::task::spawn_blocking(f)).await {
+            Ok(result) => result?,
+            Err(_) => Err(DbError::Timeout(self.timeout)),
+        }
+    }
+
+    // `write` that runs f again while it fails with a retriable error, up to write_retries times
+    async fn write_retrying<T, F>(&self, f: F) -> Result<T, DbError>
+    where
+        T: Send + 'static,
+        F: Fn(&Database) -> Result<T, DbError> + Clone + Send + 'static,
+    {
+        let mut delay = WRITE_RETRY_DELAY;
+        for _ in 0..self.write_retries {
+            match self.write(f.clone()).await {
+                Err(e) if e.is_retriable() => {
+                    tracing::warn!("Db write failed ({}), retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+        self.write(f).await
+    }
+
+    // queues f on the writer thread without waiting for it, its error is only logged
+    fn write_detached<F>(&self, f: F) -> Result<(), DbError>
+    where
+        F: FnOnce(&Database) -> Result<(), DbError> + Send + 'static,
+    {
+        *self.last_write.lock().unwrap() = Instant::now();
+        let span = tracing::debug_span!("db_write");
+        self.writer
+            .send(Box::new(move |db: &Database| {
+                let _span = span.enter();
+                if let Err(e) = f(db) {
+                    tracing::warn!("Queued db write failed: {}", e);
+                }
+            }))
+            .map_err(|_| DbError::WriterStopped)
+    }
+
+    // waits until every write queued before this call has committed, and is on disk
+    pub async fn flush(&self) -> Result<(), DbError> {
+        if matches!(self.durability, Durability::Immediate) {
+            return self.write(|_| Ok(())).await;
+        }
+        // an immediate commit persists the non-durable ones before it
+        self.write(|db| {
+            db.begin_write()?.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    // when the last write was queued, or the db opened if nothing was written yet. Reads
+    // don't count.
+    pub fn last_write(&self) -> Instant {
+        *self.last_write.lock().unwrap()
+    }
+
+    // size of the backing file in bytes, 0 for in-memory databases
+    pub fn file_size(&self) -> Result<u64, DbError> {
+        match &self.path {
+            Some(path) => std::fs::metadata(path)
+                .map(|m| m.len())
+                .map_err(DbError::from),
+            None => Ok(0),
+        }
+    }
+
+    // reclaims free pages in the backing file, returns true if anything was compacted.
+    // redb only compacts with no open transactions, so this waits for in-flight ones to
+    // finish and blocks new ones until it is done - call it when the node is quiet
+    pub async fn compact(&self) -> Result<bool, DbError> {
+        let db = self.db.clone();
+
+        self.blocking(move || {
+            let mut db = db.write().unwrap();
+            db.compact().map_err(DbError::from)
+        })
+        .await
+    }
+
+    // deletes every broadcast value and blob, the file and the other tables stay
+    pub async fn clear(&self) -> Result<(), DbError> {
+        let table_name = self.table.clone();
+
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
+            write_txn.delete_table(broadcast_table(&table_name))?;
+            write_txn.delete_table(BLOBS)?;
+            write_txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    // returns true if the id was not stored before
+    pub async fn set_broadcast_id(&self, id: u64) -> Result<bool, DbError> {
+        let table_name = self.table.clone();
+        let durability = self.durability;
+
+        let start = Instant::now();
+        let (inserted, txn) = self
+            .write_retrying(move |db| {
+                let txn_start = Instant::now();
+                let inserted = insert_broadcast_id(db, &table_name, durability, id)?;
+                Ok((inserted, txn_start.elapsed()))
+            })
+            .await?;
+        self.stats
+            .lock()
+            .unwrap()
+            .set_broadcast_id
+            .record(start.elapsed(), txn);
+        Ok(inserted)
+    }
+
+    // stores a serialized value under its hash, returns true if it was not stored before
+    pub async fn set_broadcast_blob(&self, bytes: Vec<u8>) -> Result<bool, DbError> {
+        let key = blob_key(&bytes);
+        self.write_retrying(move |db| insert_blob(db, key, &bytes))
+            .await
+    }
+
+    // the stored blobs in key order
+    pub async fn broadcast_blobs(&self) -> Result<Vec<Vec<u8>>, DbError> {
+        let db = self.db.clone();
+
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            dump_table(&read_txn, BLOBS, |_, bytes| Ok(bytes.to_vec()))
+        })
+        .await
+    }
+
+    // like `set_broadcast_id` but returns once the write is queued, not committed. Only a
+    // stopped writer fails here, a failing commit is logged by the writer thread.
+    pub fn queue_broadcast_id(&self, id: u64) -> Result<(), DbError> {
+        let table_name = self.table.clone();
+        let durability = self.durability;
+
+        self.write_detached(move |db| {
+            insert_broadcast_id(db, &table_name, durability, id)?;
+            Ok(())
+        })
+    }
+
+    // inserts all ids in a single write transaction, returns the ones that were not stored before
+    pub async fn set_broadcast_ids(&self, ids: &[u64]) -> Result<Vec<u64>, DbError> {
+        let table_name = self.table.clone();
+        let ids = ids.to_vec();
+        let durability = self.durability;
+
+        self.write_retrying(move |db| {
+            let mut write_txn = db.begin_write()?;
+            write_txn.set_durability(durability);
+            let mut inserted = vec![];
+            {
+                let mut table = write_txn.open_table(broadcast_table(&table_name))?;
+                for &id in &ids {
+                    if table.insert(id, true)?.is_none() {
+                        inserted.push(id);
+                    }
+                }
+            }
+            write_txn.commit()?;
+
+            Ok(inserted)
+        })
+        .await
+    }
+
+    pub async fn contains(&self, id: u64) -> Result<bool, DbError> {
+        let db = self.db.clone();
+        let table_name = self.table.clone();
+
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            let table = match read_txn.open_table(broadcast_table(&table_name)) {
+                Ok(table) => table,
+                Err(TableError::TableDoesNotExist(_)) => return Ok(false),
+                Err(e) => return Err(e.into()),
+            };
+
+            let value = table.get(id)?;
+            Ok(value.is_some())
+        })
+        .await
+    }
+
+    pub async fn count(&self) -> Result<u64, DbError> {
+        let db = self.db.clone();
+        let table_name = self.table.clone();
+
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            let table = match read_txn.open_table(broadcast_table(&table_name)) {
+                Ok(table) => table,
+                Err(TableError::TableDoesNotExist(_)) => return Ok(0),
+                Err(e) => return Err(e.into()),
+            };
+
+            table.len().map_err(DbError::from)
+        })
+        .await
+    }
+
+    // values come back sorted ascending and without duplicates, since that's redb's key order
+    pub async fn seen_broadcast_values(&self) -> Result<Vec<u64>, DbError> {
+        let start = Instant::now();
+        let (values, txn) = self
+            .fold_range(u64::MIN, u64::MAX, vec![], |mut values, id| {
+                values.push(id);
+                values
+            })
+            .await?;
+        self.stats
+            .lock()
+            .unwrap()
+            .seen_broadcast_values
+            .record(start.elapsed(), txn);
+        Ok(values)
+    }
+
+    // the same values as `seen_broadcast_values` collected straight into a set, for callers that
+    // only test membership
+    pub async fn seen_broadcast_set(&self) -> Result<HashSet<u64>, DbError> {
+        let start = Instant::now();
+        let (values, txn) = self
+            .fold_range(u64::MIN, u64::MAX, HashSet::new(), |mut values, id| {
+                values.insert(id);
+                values
+            })
+            .await?;
+        self.stats
+            .lock()
+            .unwrap()
+            .seen_broadcast_values
+            .record(start.elapsed(), txn);
+        Ok(values)
+    }
+
+    // like `seen_broadcast_values` but only values in `[lo, hi]`
+    pub async fn seen_broadcast_values_range(&self, lo: u64, hi: u64) -> Result<Vec<u64>, DbError> {
+        self.fold_values(lo, hi, vec![], |mut values, id| {
+            values.push(id);
+            values
+        })
+        .await
+    }
+
+    // up to `limit` values greater than `after` in order, or from the smallest without it, and
+    // the cursor to pass as `after` for the next page. There's no cursor once the last value
+    // was returned. A limit of 0 is taken as 1, an empty page couldn't tell where to go on.
+    pub async fn broadcast_page(
+        &self,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Result<(Vec<u64>, Option<u64>), DbError> {
+        let limit = limit.max(1);
+        let lo = match after {
+            Some(u64::MAX) => return Ok((vec![], None)),
+            Some(after) => after + 1,
+            None => u64::MIN,
+        };
+
+        let db = self.db.clone();
+        let table_name = self.table.clone();
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            let table = match read_txn.open_table(broadcast_table(&table_name)) {
+                Ok(table) => table,
+                Err(TableError::TableDoesNotExist(_)) => return Ok((vec![], None)),
+                Err(e) => return Err(e.into()),
+            };
+
+            // one past the page tells whether there's another one
+            let mut values = Vec::with_capacity(limit.saturating_add(1).min(1024));
+            for res in table.range(lo..)?.take(limit.saturating_add(1)) {
+                values.push(res?.0.value());
+            }
+            let next = if values.len() > limit {
+                values.truncate(limit);
+                values.last().copied()
+            } else {
+                None
+            };
+            Ok((values, next))
+        })
+        .await
+    }
+
+    // calls f with every stored value in order, without collecting them first
+    pub async fn for_each_value<F>(&self, mut f: F) -> Result<(), DbError>
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        self.fold_values(u64::MIN, u64::MAX, (), move |(), id| f(id))
+            .await
+    }
+
+    // folds the values in `[lo, hi]` in order inside the blocking task, so a caller can build
+    // exactly what it needs, e.g. a json array, without an intermediate Vec<u64>
+    pub async fn fold_values<B, F>(&self, lo: u64, hi: u64, init: B, f: F) -> Result<B, DbError>
+    where
+        B: Send + 'static,
+        F: FnMut(B, u64) -> B + Send + 'static,
+    {
+        Ok(self.fold_range(lo, hi, init, f).await?.0)
+    }
+
+    // `fold_values` plus the time spent in the read transaction
+    async fn fold_range<B, F>(
+        &self,
+        lo: u64,
+        hi: u64,
+        init: B,
+        mut f: F,
+    ) -> Result<(B, Duration), DbError>
+    where
+        B: Send + 'static,
+        F: FnMut(B, u64) -> B + Send + 'static,
+    {
+        if lo > hi {
+            return Ok((init, Duration::ZERO));
+        }
+
+        let db = self.db.clone();
+        let table_name = self.table.clone();
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let txn_start = Instant::now();
+            let read_txn = db.begin_read()?;
+            let mut acc = init;
+            {
+                let table = match read_txn.open_table(broadcast_table(&table_name)) {
+                    Ok(table) => table,
+                    Err(TableError::TableDoesNotExist(_)) => return Ok((acc, txn_start.elapsed())),
+                    Err(e) => return Err(e.into()),
+                };
+
+                let iter = table.range(lo..=hi)?;
+                for res in iter {
+                    let (id, _) = res?;
+                    acc = f(acc, id.value());
+                }
+            }
+
+            Ok((acc, txn_start.elapsed()))
+        })
+        .await
+    }
+
+    // everything stored, for debugging a node after a failed run. Reads every table in one
+    // transaction, so keep it off hot paths.
+    pub async fn export(&self) -> Result<Value, DbError> {
+        let db = self.db.clone();
+        let table_name = self.table.clone();
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            let object = |entries: Vec<(String, Value)>| Value::Object(entries.into_iter().collect());
+            Ok(json!({
+                "broadcast": dump_table(&read_txn, broadcast_table(&table_name), |id, _| Ok(id))?,
+                "blobs": dump_table(&read_txn, BLOBS, |_, bytes| Ok(serde_json::from_slice::<Value>(bytes)?))?,
+                "values": object(dump_table(&read_txn, VALUES, |k, v| Ok((k.to_string(), v.into())))?),
+                "topology": object(dump_table(&read_txn, TOPOLOGY, |node, peers| {
+                    Ok((node.to_string(), serde_json::from_str(peers)?))
+                })?),
+                "counters": object(dump_table(&read_txn, COUNTER, |node, n| {
+                    Ok((node.to_string(), n.into()))
+                })?),
+                "decrements": object(dump_table(&read_txn, COUNTER_NEG, |node, n| {
+                    Ok((node.to_string(), n.into()))
+                })?),
+                "registers": object(dump_table(&read_txn, REGISTERS, |k, v| {
+                    Ok((k.to_string(), v.into()))
+                })?),
+                "elements": dump_table(&read_txn, VALUES_STR, |element, ()| Ok(element.to_string()))?,
+                "kv": object(dump_table(&read_txn, KV, |k, v| Ok((k.to_string(), v.into())))?),
+                "meta": object(dump_table(&read_txn, META, |k, v| Ok((k.to_string(), v.into())))?),
+                "logs": crate::log::export_logs(&read_txn)?,
+                "committed_offsets": crate::log::export_committed_offsets(&read_txn)?,
+            }))
+        })
+        .await
+    }
+
+    pub async fn export_json(&self) -> Result<String, DbError> {
+        Ok(serde_json::to_string(&self.export().await?)?)
+    }
+
+    pub async fn set_value(&self, key: u64, value: u64) -> Result<(), DbError> {
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
+            {
+                let mut table = write_txn.open_table(VALUES)?;
+                table.insert(key, value)?;
+            }
+            write_txn.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    // returns true if the element was not stored before
+    pub async fn add_element(&self, element: &str) -> Result<bool, DbError> {
+        Ok(!self
+            .add_elements(vec![element.to_string()])
+            .await?
+            .is_empty())
     }
 
-    pub async fn set_broadcast_id(&self, id: u64) -> Result<(), String> {
+    // set union with elements in one write transaction, returns the ones that were new
+    pub async fn add_elements(&self, elements: Vec<String>) -> Result<Vec<String>, DbError> {
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
+            let mut inserted = vec![];
+            {
+                let mut table = write_txn.open_table(VALUES_STR)?;
+                for element in elements {
+                    if table.insert(element.as_str(), ())?.is_none() {
+                        inserted.push(element);
+                    }
+                }
+            }
+            write_txn.commit()?;
+
+            Ok(inserted)
+        })
+        .await
+    }
+
+    pub async fn g_set(&self) -> Result<GSet, DbError> {
+        Ok(self.all_elements().await?.into_iter().collect())
+    }
+
+    // union with a peer's set, returns the elements that were new
+    pub async fn merge_g_set(&self, other: GSet) -> Result<Vec<String>, DbError> {
+        self.add_elements(other.into_elements()).await
+    }
+
+    // sorted, per redb key order
+    pub async fn all_elements(&self) -> Result<Vec<String>, DbError> {
+        let mut elements = vec![];
+
         let db = self.db.clone();
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            {
+                let table = match read_txn.open_table(VALUES_STR) {
+                    Ok(table) => table,
+                    Err(TableError::TableDoesNotExist(_)) => return Ok(elements),
+                    Err(e) => return Err(e.into()),
+                };
+
+                for res in table.iter()? {
+                    let (element, _) = res?;
+                    elements.push(element.value().to_string());
+                }
+            }
+
+            Ok(elements)
+        })
+        .await
+    }
 
-        tokio::task::spawn_blocking(move || {
-            let write_txn = db.begin_write().map_err(|e| e.to_string())?;
+    pub async fn set_id_limit(&self, limit: u64) -> Result<(), DbError> {
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
             {
-                let mut table = write_txn.open_table(TABLE).map_err(|e| e.to_string())?;
-                table.insert(id, true).map_err(|e| e.to_string())?;
+                let mut table = write_txn.open_table(META)?;
+                table.insert(ID_LIMIT, limit)?;
             }
-            write_txn.commit().map_err(|e| e.to_string())?;
+            write_txn.commit()?;
 
             Ok(())
         })
         .await
-        .map_err(|e| e.to_string())?
     }
 
-    pub async fn seen_broadcast_values(&self) -> Result<Vec<u64>, String> {
-        let mut values = vec![];
+    // 0 if no id was ever reserved
+    pub async fn id_limit(&self) -> Result<u64, DbError> {
+        let db = self.db.clone();
+
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            let table = match read_txn.open_table(META) {
+                Ok(table) => table,
+                Err(TableError::TableDoesNotExist(_)) => return Ok(0),
+                Err(e) => return Err(e.into()),
+            };
+
+            let limit = table.get(ID_LIMIT)?;
+            Ok(limit.map(|v| v.value()).unwrap_or_default())
+        })
+        .await
+    }
 
+    pub async fn get_value(&self, key: u64) -> Result<Option<u64>, DbError> {
         let db = self.db.clone();
-        tokio::task::spawn_blocking(move || {
-            let read_txn = db.begin_read().map_err(|e| e.to_string())?;
+
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            let table = match read_txn.open_table(VALUES) {
+                Ok(table) => table,
+                Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            let value = table.get(key)?;
+            Ok(value.map(|v| v.value()))
+        })
+        .await
+    }
+
+    pub async fn kv_read(&self, key: u64) -> Result<Option<u64>, DbError> {
+        let db = self.db.clone();
+
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            let table = match read_txn.open_table(KV) {
+                Ok(table) => table,
+                Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            let value = table.get(key)?;
+            Ok(value.map(|v| v.value()))
+        })
+        .await
+    }
+
+    pub async fn kv_write(&self, key: u64, value: u64) -> Result<(), DbError> {
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
+            {
+                let mut table = write_txn.open_table(KV)?;
+                table.insert(key, value)?;
+            }
+            write_txn.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    // compare and swap in one write transaction, nothing is written unless key holds from
+    pub async fn kv_cas(&self, key: u64, from: u64, to: u64) -> Result<(), DbError> {
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
+            {
+                let mut table = write_txn.open_table(KV)?;
+                let current = table
+                    .get(key)?
+                    .map(|v| v.value())
+                    .ok_or(DbError::KeyNotFound(key))?;
+                if current != from {
+                    return Err(DbError::CasMismatch { key, from, current });
+                }
+                table.insert(key, to)?;
+            }
+            write_txn.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_topology(
+        &self,
+        topology: HashMap<String, Vec<String>>,
+    ) -> Result<(), DbError> {
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
             {
-                let table = match read_txn.open_table(TABLE) {
+                let mut table = write_txn.open_table(TOPOLOGY)?;
+                for (node_id, peers) in topology {
+                    let peers = serde_json::to_string(&peers)?;
+                    table.insert(node_id.as_str(), peers.as_str())?;
+                }
+            }
+            write_txn.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn topology(&self) -> Result<HashMap<String, Vec<String>>, DbError> {
+        let mut topology = HashMap::new();
+
+        let db = self.db.clone();
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            {
+                let table = match read_txn.open_table(TOPOLOGY) {
                     Ok(table) => table,
-                    Err(TableError::TableDoesNotExist(_)) => return Ok(values),
-                    Err(e) => return Err(e.to_string()),
+                    Err(TableError::TableDoesNotExist(_)) => return Ok(topology),
+                    Err(e) => return Err(e.into()),
                 };
 
-                let iter = table.iter().map_err(|e| e.to_string())?;
+                let iter = table.iter()?;
                 for res in iter {
-                    if let Ok(val) = res {
-                        values.push(val.0.value());
-                    } else {
-                        return Err("Failed to read broadcast values".to_string());
+                    let (node_id, peers) = res?;
+                    let peers: Vec<String> = serde_json::from_str(peers.value())?;
+                    topology.insert(node_id.value().to_string(), peers);
+                }
+            }
+
+            Ok(topology)
+        })
+        .await
+    }
+
+    // positive deltas grow the node's increment sum, negative ones its decrement sum, so both
+    // stay grow-only and merge by max no matter what order peers' states arrive in.
+    // the read and the increment share one write transaction and redb runs write transactions
+    // one at a time, so concurrent adds can't lose updates
+    pub async fn add(&self, node_id: &str, delta: i64) -> Result<(), DbError> {
+        let node_id = node_id.to_string();
+        let table = if delta < 0 { COUNTER_NEG } else { COUNTER };
+
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
+            {
+                let mut table = write_txn.open_table(table)?;
+                let current = table
+                    .get(node_id.as_str())?
+                    .map(|v| v.value())
+                    .unwrap_or_default();
+                let Some(sum) = current.checked_add(delta.unsigned_abs()) else {
+                    return Err(DbError::CounterOverflow { node_id });
+                };
+                table.insert(node_id.as_str(), sum)?;
+            }
+            write_txn.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    // both per-node sum tables, empty for a node that never counted anything. Read in one
+    // transaction, an add committing between the two would give a total that never existed.
+    pub async fn pn_counter(&self) -> Result<PNCounter, DbError> {
+        let db = self.db.clone();
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            Ok(PNCounter {
+                increments: counter_table(&read_txn, COUNTER)?,
+                decrements: counter_table(&read_txn, COUNTER_NEG)?,
+            })
+        })
+        .await
+    }
+
+    // g-counter value, the sum of increments only
+    pub async fn counter_total(&self) -> Result<u64, DbError> {
+        self.pn_counter().await.map(|c| c.increments.value())
+    }
+
+    // pn-counter value, increments minus decrements across all nodes
+    pub async fn pn_total(&self) -> Result<i64, DbError> {
+        self.pn_counter().await.map(|c| c.value())
+    }
+
+    // merges a peer's counter into the stored one in a single write transaction, only the
+    // sums that grew are written back
+    pub async fn merge_counters(&self, other: PNCounter) -> Result<(), DbError> {
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
+            for (definition, theirs) in
+                [(COUNTER, other.increments), (COUNTER_NEG, other.decrements)]
+            {
+                if theirs.sums().is_empty() {
+                    continue;
+                }
+                let mut table = write_txn.open_table(definition)?;
+                let mut ours = GCounter::default();
+                for res in table.iter()? {
+                    let (node_id, value) = res?;
+                    ours.increment(node_id.value(), value.value());
+                }
+                let before = ours.clone();
+                ours.merge(&theirs);
+                for (node_id, value) in ours.sums() {
+                    if before.sums().get(node_id) != Some(value) {
+                        table.insert(node_id.as_str(), *value)?;
+                    }
+                }
+            }
+            write_txn.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    // applies all ops inside a single write transaction, so a txn is either fully applied or not at all
+    pub async fn apply_txn(&self, ops: Vec<TxnOp>) -> Result<Vec<TxnOp>, DbError> {
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
+            let mut results = Vec::with_capacity(ops.len());
+            {
+                let mut table = write_txn.open_table(REGISTERS)?;
+                for op in ops {
+                    match op {
+                        TxnOp::Read(key, _) => {
+                            let value = table.get(key)?.map(|v| v.value());
+                            results.push(TxnOp::Read(key, value));
+                        }
+                        TxnOp::Write(key, value) => {
+                            table.insert(key, value)?;
+                            results.push(TxnOp::Write(key, value));
+                        }
                     }
                 }
             }
+            write_txn.commit()?;
 
-            Ok(values)
+            Ok(results)
         })
         .await
-        .map_err(|e| e.to_string())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+    #[tokio::test]
+    async fn repeated_broadcast_id_is_not_new() {
+        let db = Db::new_in_memory().unwrap();
+        assert!(db.set_broadcast_id(7).await.unwrap());
+        assert!(!db.set_broadcast_id(7).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn seen_values_come_back_sorted_without_duplicates() {
+        let db = Db::new_in_memory().unwrap();
+        for id in [42, 7, 19, 7, 3, 42] {
+            db.set_broadcast_id(id).await.unwrap();
+        }
+        assert_eq!(
+            db.seen_broadcast_values().await.unwrap(),
+            vec![3, 7, 19, 42]
+        );
+    }
+
+    #[tokio::test]
+    async fn ten_thousand_ids_in_one_call() {
+        let db = Db::new_in_memory().unwrap();
+        let ids: Vec<u64> = (0..10_000).rev().collect();
+        assert_eq!(db.set_broadcast_ids(&ids).await.unwrap().len(), 10_000);
+        assert!(db.set_broadcast_ids(&ids).await.unwrap().is_empty());
+        assert_eq!(db.count().await.unwrap(), 10_000);
+    }
+
+    #[tokio::test]
+    async fn contains_present_and_absent() {
+        let db = Db::new_in_memory().unwrap();
+        // no table yet
+        assert!(!db.contains(1).await.unwrap());
+        db.set_broadcast_id(1).await.unwrap();
+        assert!(db.contains(1).await.unwrap());
+        assert!(!db.contains(2).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn get_value_hit_and_miss() {
+        let db = Db::new_in_memory().unwrap();
+        assert_eq!(db.get_value(1).await.unwrap(), None);
+        db.set_value(1, 10).await.unwrap();
+        assert_eq!(db.get_value(1).await.unwrap(), Some(10));
+        assert_eq!(db.get_value(2).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn pn_counters_merged_in_either_order_agree() {
+        let (a, b) = (Db::new_in_memory().unwrap(), Db::new_in_memory().unwrap());
+        a.add("n1", 5).await.unwrap();
+        a.add("n1", -2).await.unwrap();
+        b.add("n2", -4).await.unwrap();
+        b.add("n2", 3).await.unwrap();
+
+        let (a_state, b_state) = (a.pn_counter().await.unwrap(), b.pn_counter().await.unwrap());
+        a.merge_counters(b_state).await.unwrap();
+        b.merge_counters(a_state).await.unwrap();
+        let total = a.pn_counter().await.unwrap().value();
+        assert_eq!(total, 2);
+        assert_eq!(b.pn_counter().await.unwrap().value(), total);
+    }
+
+    #[tokio::test]
+    async fn totals_sum_every_node() {
+        let db = Db::new_in_memory().unwrap();
+        db.add("n1", 5).await.unwrap();
+        db.add("n2", 4).await.unwrap();
+        db.add("n1", -2).await.unwrap();
+        assert_eq!(db.counter_total().await.unwrap(), 9);
+        assert_eq!(db.pn_total().await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn add_past_u64_max_is_rejected() {
+        let db = Db::new_in_memory().unwrap();
+        db.add("n1", i64::MAX).await.unwrap();
+        db.add("n1", i64::MAX).await.unwrap();
+        assert!(matches!(
+            db.add("n1", 2).await,
+            Err(DbError::CounterOverflow { .. })
+        ));
+        let increments = db.pn_counter().await.unwrap().increments;
+        assert_eq!(increments.sums()["n1"], u64::MAX - 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_adds_lose_no_updates() {
+        let db = Arc::new(Db::new_in_memory().unwrap());
+        let adds: Vec<_> = (0..500)
+            .map(|i| {
+                let db = db.clone();
+                // two nodes, so both new and existing rows race
+                tokio::spawn(async move { db.add(["n1", "n2"][i % 2], 1).await })
+            })
+            .collect();
+        for add in adds {
+            add.await.unwrap().unwrap();
+        }
+        assert_eq!(db.pn_counter().await.unwrap().value(), 500);
+    }
+
+    // every merge moves both sums together, so a read seeing one without the other would
+    // show a total other than 0
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn counter_reads_see_both_sums_of_one_commit() {
+        let db = Arc::new(Db::new_in_memory().unwrap());
+        let merges = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for n in 1..300 {
+                    let mut counter = PNCounter::default();
+                    counter.increments.increment("n1", n);
+                    counter.decrements.increment("n1", n);
+                    db.merge_counters(counter).await.unwrap();
+                }
+            })
+        };
+        while !merges.is_finished() {
+            assert_eq!(db.pn_counter().await.unwrap().value(), 0);
+        }
+        merges.await.unwrap();
+    }
+
+    // the only blocking thread is kept busy until every write is in, so the writes can't
+    // have gone through the blocking pool
+    #[test]
+    fn rapid_writes_bypass_the_blocking_pool() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .max_blocking_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            // the last write waits behind all the queued ones
+            let db = Db::new_in_memory()
+                .unwrap()
+                .with_timeout(Duration::from_secs(60));
+            let (done, wait) = std::sync::mpsc::channel::<()>();
+            let busy = tokio::task::spawn_blocking(move || wait.recv());
+
+            for id in 0..100_000 {
+                db.queue_broadcast_id(id).unwrap();
+            }
+            assert!(db.set_broadcast_id(100_000).await.unwrap());
+            db.flush().await.unwrap();
+            done.send(()).unwrap();
+            busy.await.unwrap().unwrap();
+            assert_eq!(db.count().await.unwrap(), 100_001);
+        });
+    }
+
+    #[tokio::test]
+    async fn disjoint_g_sets_merge_to_their_union() {
+        let (a, b) = (Db::new_in_memory().unwrap(), Db::new_in_memory().unwrap());
+        a.add_element("x").await.unwrap();
+        b.add_element("y").await.unwrap();
+
+        let (a_set, b_set) = (a.g_set().await.unwrap(), b.g_set().await.unwrap());
+        assert_eq!(a.merge_g_set(b_set).await.unwrap(), vec!["y"]);
+        assert_eq!(b.merge_g_set(a_set).await.unwrap(), vec!["x"]);
+        assert_eq!(a.all_elements().await.unwrap(), vec!["x", "y"]);
+        assert_eq!(b.all_elements().await.unwrap(), vec!["x", "y"]);
+    }
+
+    // a file of the given name in a fresh directory under the system temp dir
+    fn temp_path(name: &str) -> PathBuf {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir()
+            .join(format!("gossip-db-test-{}-{}", std::process::id(), n))
+            .join(name)
+    }
+
+    #[test]
+    fn corrupt_file_is_only_recreated_when_asked() {
+        let path = temp_path("n1.redb");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"not a redb file").unwrap();
+
+        assert!(Db::new_at(&path).is_err());
+        assert!(Db::open(&path, false).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a redb file");
+
+        assert!(Db::open(&path, true).is_ok());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn reopened_db_keeps_the_id_limit() {
+        let path = temp_path("n1.redb");
+        {
+            let db = Db::new_at(&path).unwrap();
+            assert!(!db.recovered());
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(db.set_id_limit(2000))
+                .unwrap();
+        }
+        // the writer thread lets go of the file once it sees the db dropped
+        let reopened = loop {
+            match Db::new_at(&path) {
+                Err(DbError::Redb(e)) if matches!(*e, redb::Error::DatabaseAlreadyOpen) => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                db => break db.unwrap(),
+            }
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        assert_eq!(rt.block_on(reopened.id_limit()).unwrap(), 2000);
+        drop(reopened);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn recovered_db_says_so() {
+        let path = temp_path("n1.redb");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"not a redb file").unwrap();
+        assert!(Db::open(&path, true).unwrap().recovered());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cas_swaps_only_from_the_current_value() {
+        let db = Db::new_in_memory().unwrap();
+        assert!(matches!(
+            db.kv_cas(1, 0, 1).await,
+            Err(DbError::KeyNotFound(1))
+        ));
+
+        db.kv_write(1, 10).await.unwrap();
+        db.kv_cas(1, 10, 11).await.unwrap();
+        assert_eq!(db.kv_read(1).await.unwrap(), Some(11));
+
+        assert!(matches!(
+            db.kv_cas(1, 10, 12).await,
+            Err(DbError::CasMismatch {
+                key: 1,
+                from: 10,
+                current: 11
+            })
+        ));
+        assert_eq!(db.kv_read(1).await.unwrap(), Some(11));
+    }
+
+    #[tokio::test]
+    async fn colliding_blobs_are_both_kept() {
+        let db = Db::new_in_memory().unwrap();
+        assert!(db.write(|db| insert_blob(db, 1, b"a")).await.unwrap());
+        assert!(db.write(|db| insert_blob(db, 1, b"b")).await.unwrap());
+        assert!(!db.write(|db| insert_blob(db, 1, b"b")).await.unwrap());
+        assert!(!db.write(|db| insert_blob(db, 1, b"a")).await.unwrap());
+        assert_eq!(
+            db.broadcast_blobs().await.unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+    }
+
+    // the published FNV-1a test vectors, a build whose keys differ couldn't find old blobs
+    #[test]
+    fn blob_keys_are_fnv_1a() {
+        assert_eq!(blob_key(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(blob_key(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(blob_key(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    // fails with a busy disk on the first try only
+    #[tokio::test]
+    async fn transient_failure_is_retried() {
+        let db = Db::new_in_memory().unwrap();
+        let attempts = Arc::new(AtomicU32::new(0));
+        let write = {
+            let attempts = attempts.clone();
+            move |db: &Database| {
+                if attempts.fetch_add(1, Ordering::Relaxed) == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::Interrupted).into());
+                }
+                insert_broadcast_id(db, DEFAULT_TABLE, Durability::Immediate, 1)
+            }
+        };
+        assert!(db.write_retrying(write).await.unwrap());
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert!(db.contains(1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn fatal_failure_is_not_retried() {
+        let db = Db::new_in_memory().unwrap();
+        let attempts = Arc::new(AtomicU32::new(0));
+        let write = {
+            let attempts = attempts.clone();
+            move |_: &Database| -> Result<(), DbError> {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err(std::io::Error::from(std::io::ErrorKind::InvalidData).into())
+            }
+        };
+        assert!(db.write_retrying(write).await.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn thousand_values_in_pages_of_a_hundred() {
+        let db = Db::new_in_memory().unwrap();
+        let ids: Vec<u64> = (0..1000).map(|id| id * 3).collect();
+        db.set_broadcast_ids(&ids).await.unwrap();
+
+        let (mut read, mut pages, mut after) = (vec![], 0, None);
+        loop {
+            let (values, next) = db.broadcast_page(after, 100).await.unwrap();
+            assert_eq!(values.len(), 100);
+            read.extend(values);
+            pages += 1;
+            match next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages, 10);
+        assert_eq!(read, ids);
+    }
+
+    #[tokio::test]
+    async fn zero_limit_still_advances() {
+        let db = Db::new_in_memory().unwrap();
+        db.set_broadcast_ids(&[1, 2]).await.unwrap();
+        assert_eq!(
+            db.broadcast_page(None, 0).await.unwrap(),
+            (vec![1], Some(1))
+        );
+        assert_eq!(
+            db.broadcast_page(Some(1), 0).await.unwrap(),
+            (vec![2], None)
+        );
+    }
+
+    #[tokio::test]
+    async fn clear_empties_a_populated_db() {
+        let db = Db::new_in_memory().unwrap();
+        db.set_broadcast_ids(&[1, 2, 3]).await.unwrap();
+        db.set_broadcast_blob(b"{}".to_vec()).await.unwrap();
+
+        db.clear().await.unwrap();
+        assert!(db.seen_broadcast_values().await.unwrap().is_empty());
+        assert!(db.broadcast_blobs().await.unwrap().is_empty());
+        // and takes new values again
+        assert!(db.set_broadcast_id(2).await.unwrap());
+        assert_eq!(db.seen_broadcast_values().await.unwrap(), vec![2]);
+    }
+
+    // the caller gives up, the write itself goes on and commits
+    #[tokio::test]
+    async fn slow_write_times_out_but_still_commits() {
+        let db = Db::new_in_memory()
+            .unwrap()
+            .with_timeout(Duration::from_millis(50));
+        let slow = db.write(|db| {
+            std::thread::sleep(Duration::from_millis(200));
+            insert_broadcast_id(db, DEFAULT_TABLE, Durability::Immediate, 1)
+        });
+        assert!(matches!(slow.await, Err(DbError::Timeout(_))));
+
+        // the flush is queued behind the slow write
+        let db = db.with_timeout(Duration::from_secs(5));
+        db.flush().await.unwrap();
+        assert!(db.contains(1).await.unwrap());
     }
 }
@@ -0,0 +1,2695 @@
+use crate::addressbook::AddressBook;
+use crate::config::{Config, IdScheme, WorkloadKind};
+use crate::crdt::{Crdt, PNCounter};
+use crate::db::{Db, DbError, TxnOp};
+use crate::merkle::{self, MerkleSummary};
+use crate::protocol::{KvRequest, Request, Response, Topology};
+use async_trait::async_trait;
+use maelstrom::protocol::{ErrorMessageBody, Message, MessageBody};
+use maelstrom::{done, Error, Node, Result, Runtime};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify, OnceCell};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, enabled, info, info_span, warn, Instrument, Level};
+
+// retries back off exponentially from the retry interval up to this
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(8);
+// unacked values kept per peer, past this the oldest are dropped and left to anti-entropy
+const MAX_PENDING_PER_PEER: usize = 10_000;
+const METRICS_INTERVAL: Duration = Duration::from_secs(5);
+// how often peers are read for their counts, see `Config::convergence_window`
+const CONVERGENCE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+// how often the db is checked for having gone idle, see `Config::compact_idle`
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// upper bound on entries returned per key by one poll, clients poll again from the last offset
+const MAX_POLL_ENTRIES: usize = 100;
+// values per page of a paged broadcast read, also its default page size
+const MAX_READ_PAGE: usize = 1000;
+const RPC_TIMEOUT: Duration = Duration::from_millis(1000);
+// maelstrom's linearizable key-value service, addressed like a node. seq-kv and lww-kv take the
+// same requests but give weaker guarantees.
+const LIN_KV: &str = "lin-kv";
+// share of anti-entropy rounds that sync with one of the nearest peers, the others pick from
+// every peer so far away nodes still get a direct sync now and then
+const NEAR_SYNC_SHARE: f64 = 0.8;
+// node-counter ids are reserved this many at a time, a restart continues after the last
+// reserved block since any id in it may have been handed out already
+const ID_BLOCK: u64 = 1000;
+// state-changing requests whose replies are kept to answer resends, oldest evicted first
+const MAX_CACHED_REPLIES: usize = 10_000;
+// messages held while init opens the db, past this they get temporarily-unavailable instead
+const MAX_PRE_INIT_MESSAGES: usize = 1000;
+// snowflake ids: 41 bits of milliseconds since SNOWFLAKE_EPOCH_MS, 10 worker bits derived from
+// the node id and a 12 bit per-millisecond sequence
+const SNOWFLAKE_EPOCH_MS: u64 = 1_704_067_200_000; // 2024-01-01
+const SNOWFLAKE_WORKER_BITS: u32 = 10;
+const SNOWFLAKE_SEQUENCE_BITS: u32 = 12;
+
+// rough message amplification signal, logged every METRICS_INTERVAL
+#[derive(Default)]
+struct Metrics {
+    broadcasts_received: AtomicU64,
+    broadcasts_forwarded: AtomicU64,
+    reads_served: AtomicU64,
+    acks_received: AtomicU64,
+    pending_dropped: AtomicU64,
+}
+
+impl Metrics {
+    fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// replies to recent state-changing requests keyed by (src, msg_id), so a resent request is
+// answered again instead of applied twice
+#[derive(Default)]
+struct ReplyCache {
+    replies: HashMap<(String, u64), Option<MessageBody>>,
+    order: VecDeque<(String, u64)>,
+}
+
+impl ReplyCache {
+    // None for a request not seen before, which is tracked from then on. Otherwise the stored
+    // reply, itself None while the first copy is still being handled.
+    fn begin(&mut self, key: (String, u64)) -> Option<Option<MessageBody>> {
+        if let Some(reply) = self.replies.get(&key) {
+            return Some(reply.clone());
+        }
+        self.replies.insert(key.clone(), None);
+        self.order.push_back(key);
+        while self.order.len() > MAX_CACHED_REPLIES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+        None
+    }
+
+    fn finish(&mut self, key: &(String, u64), reply: MessageBody) {
+        if let Some(slot) = self.replies.get_mut(key) {
+            *slot = Some(reply);
+        }
+    }
+
+    // the request failed or wasn't handled, a resend gets processed from scratch
+    fn abandon(&mut self, key: &(String, u64)) {
+        if let Some(None) = self.replies.get(key) {
+            self.replies.remove(key);
+        }
+    }
+}
+
+// log lines that can come once per message, e.g. for a message type nobody handles. Only the
+// first of a kind is logged, repeats are counted and summed up every METRICS_INTERVAL.
+#[derive(Default)]
+struct LogThrottle {
+    repeats: BTreeMap<String, u64>,
+}
+
+impl LogThrottle {
+    // true if kind wasn't seen since the last summary, i.e. the line should be logged
+    fn first(&mut self, kind: &str) -> bool {
+        match self.repeats.get_mut(kind) {
+            Some(repeats) => {
+                *repeats += 1;
+                false
+            }
+            None => {
+                self.repeats.insert(kind.to_string(), 0);
+                true
+            }
+        }
+    }
+
+    // repeats per kind since the last summary, kinds that came up only once are left out
+    fn summary(&mut self) -> BTreeMap<String, u64> {
+        let mut repeats = std::mem::take(&mut self.repeats);
+        repeats.retain(|_, n| *n > 0);
+        repeats
+    }
+}
+
+// one broadcast batch waiting for a sender task
+struct Outgoing {
+    node: String,
+    messages: Vec<u64>,
+    hops: u8,
+    seen_by: Vec<String>,
+}
+
+// how a queued value is passed on: the relays it has left and the nodes known to have it
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct Relay {
+    hops: u8,
+    seen_by: BTreeSet<String>,
+}
+
+impl Relay {
+    // the same value queued twice keeps the most hops and only the nodes both copies agree on
+    fn merge(&mut self, other: Relay) {
+        self.hops = self.hops.max(other.hops);
+        self.seen_by = self.seen_by.intersection(&other.seen_by).cloned().collect();
+    }
+}
+
+// backoff state of one unacked value sent to a peer
+#[derive(Clone, Copy, Debug)]
+struct PendingRetry {
+    attempts: u32,
+    next_retry: Instant,
+    // relays the value has left once the peer stores it
+    hops: u8,
+    // when the value was first queued for the peer, decides what gets dropped over the cap
+    queued_at: Instant,
+}
+
+impl PendingRetry {
+    fn new(base: Duration, hops: u8) -> Self {
+        let now = Instant::now();
+        Self {
+            attempts: 0,
+            next_retry: now + base,
+            hops,
+            queued_at: now,
+        }
+    }
+
+    fn retried(self, base: Duration, rng: &mut impl Rng) -> Self {
+        let attempts = self.attempts.saturating_add(1);
+        Self {
+            attempts,
+            next_retry: Instant::now() + backoff(base, attempts, rng),
+            ..self
+        }
+    }
+}
+
+// an undirected edge, the smaller node id first
+type Edge = (String, String);
+
+// the edges of the topology last applied and the spanning tree built over them, kept so a
+// topology that didn't change skips the rebuild
+#[derive(Default)]
+struct SpanningTree {
+    edges: BTreeSet<Edge>,
+    tree: BTreeSet<Edge>,
+}
+
+impl SpanningTree {
+    // swaps in the new edges and rebuilds the tree from them alone. Patching the old tree
+    // would make it depend on the order topologies arrived in, and nodes that saw different
+    // ones would forward along different trees.
+    fn update(&mut self, edges: BTreeSet<Edge>) {
+        let added: Vec<&Edge> = edges.difference(&self.edges).collect();
+        let removed: Vec<&Edge> = self.edges.difference(&edges).collect();
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+        info!("Topology changed, added {:?}, removed {:?}", added, removed);
+
+        let tree = build_tree(&edges);
+        if tree != self.tree {
+            debug!("Spanning tree changed to {:?}", tree);
+        }
+        self.tree = tree;
+        self.edges = edges;
+    }
+
+    fn neighbours(&self, node_id: &str) -> HashSet<String> {
+        self.tree
+            .iter()
+            .filter_map(|(a, b)| match node_id {
+                _ if a == node_id => Some(b.clone()),
+                _ if b == node_id => Some(a.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// what a node holds in memory, for comparing a node before and after a restart. Maps are
+// ordered and lists sorted so equal states compare and serialize equal.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct HandlerSnapshot {
+    pub seen: Vec<u64>,
+    // every known node and its peers
+    pub addressbook: BTreeMap<String, Vec<String>>,
+    pub tree_neighbours: Vec<String>,
+    // values waiting for the next batch and values sent but unacked, by peer
+    pub outbox: BTreeMap<String, Vec<u64>>,
+    pub pending: BTreeMap<String, Vec<u64>>,
+}
+
+// locks that are held together are always taken in this order: spanning_tree, addressbook,
+// tree_neighbours, distances, outbox, pending, rng, seen, merkle. Any subset is fine as long as
+// the order is kept, e.g. pending then rng when rescheduling retries.
+// messages held until init is done, see `hold_until_init`
+#[derive(Default)]
+struct PreInit {
+    held: VecDeque<Message>,
+    // whether init succeeded, once it's over. Nothing is held after that.
+    finished: Option<bool>,
+}
+
+pub struct Handler {
+    db: OnceCell<Db>,
+    // messages that arrived before init was done, replayed by `replay_pre_init`
+    pre_init: Mutex<PreInit>,
+    // signalled once init is done, whether it succeeded or not
+    initialized: Notify,
+    addressbook: AddressBook,
+    // broadcast values sent to a peer that haven't been acked yet, keyed by peer
+    pending: Arc<Mutex<HashMap<String, HashMap<u64, PendingRetry>>>>,
+    // values waiting for the next batch flush with how to relay them, keyed by peer
+    outbox: Arc<Mutex<HashMap<String, HashMap<u64, Relay>>>>,
+    // batches handed to the sender tasks, so a large fanout queues up instead of putting
+    // every call in flight at once
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    outgoing_rx: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<Outgoing>>>,
+    // in-memory copy of the stored broadcast values so reads don't hit redb, the db stays
+    // the durable source the cache is rebuilt from on init. Ordered so reads come back sorted.
+    seen: Arc<RwLock<BTreeSet<u64>>>,
+    // summary of `seen` for `sync_merkle`, only changed together with it, see `add_seen`
+    merkle: Mutex<MerkleSummary>,
+    // the stored blob values by their serialized bytes, like `seen` for the integers
+    blobs: RwLock<BTreeMap<Vec<u8>, Value>>,
+    // this node's parent and children in the spanning tree built from the last topology
+    tree_neighbours: Arc<Mutex<HashSet<String>>>,
+    spanning_tree: Mutex<SpanningTree>,
+    // hops from this node to every node it can reach, from the last topology
+    distances: Mutex<HashMap<String, usize>>,
+    config: Config,
+    metrics: Arc<Metrics>,
+    id_counter: AtomicU64,
+    // end of the id block reserved in the db, ids from id_counter stay below it
+    id_limit: tokio::sync::Mutex<u64>,
+    replies: Mutex<ReplyCache>,
+    // unhandled and unparseable messages by type
+    unhandled: Mutex<LogThrottle>,
+    // every random choice goes through this, reseeded from the node id on init, see `Config::seed`
+    rng: Mutex<StdRng>,
+    // last snowflake handed out as `millis << SNOWFLAKE_SEQUENCE_BITS | sequence`
+    snowflake: AtomicU64,
+}
+
+impl Default for Handler {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+impl Handler {
+    pub fn new(config: Config) -> Self {
+        let rng = StdRng::seed_from_u64(config.seed.unwrap_or_default());
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        Self {
+            db: OnceCell::new(),
+            pre_init: Mutex::default(),
+            initialized: Notify::new(),
+            addressbook: AddressBook::default(),
+            pending: Arc::default(),
+            outbox: Arc::default(),
+            outgoing,
+            outgoing_rx: Arc::new(tokio::sync::Mutex::new(outgoing_rx)),
+            seen: Arc::default(),
+            merkle: Mutex::default(),
+            blobs: RwLock::default(),
+            tree_neighbours: Arc::default(),
+            spanning_tree: Mutex::default(),
+            distances: Mutex::default(),
+            config,
+            metrics: Arc::default(),
+            id_counter: AtomicU64::new(0),
+            id_limit: tokio::sync::Mutex::new(0),
+            snowflake: AtomicU64::new(0),
+            replies: Mutex::default(),
+            unhandled: Mutex::default(),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    // starts the background loops the workload needs, they run until aborted
+    pub fn spawn_tasks(self: &Arc<Self>, runtime: &Runtime) -> Vec<JoinHandle<()>> {
+        let mut tasks = vec![];
+        for _ in 0..self.config.senders {
+            let runtime = runtime.clone();
+            let handler = self.clone();
+            tasks.push(tokio::spawn(
+                async move { handler.send_gossip(runtime).await },
+            ));
+        }
+        {
+            let handler = self.clone();
+            tasks.push(tokio::spawn(async move { handler.retry_unacked().await }));
+        }
+        if self.config.workload == WorkloadKind::Broadcast {
+            {
+                let runtime = runtime.clone();
+                let handler = self.clone();
+                tasks.push(tokio::spawn(
+                    async move { handler.anti_entropy(runtime).await },
+                ));
+            }
+            {
+                let handler = self.clone();
+                tasks.push(tokio::spawn(async move { handler.flush_batches().await }));
+            }
+            if let Some(window) = self.config.convergence_window {
+                let runtime = runtime.clone();
+                let handler = self.clone();
+                tasks.push(tokio::spawn(async move {
+                    handler.check_convergence(runtime, window).await
+                }));
+            }
+        }
+        if matches!(
+            self.config.workload,
+            WorkloadKind::GCounter | WorkloadKind::PnCounter | WorkloadKind::GSet
+        ) {
+            let runtime = runtime.clone();
+            let handler = self.clone();
+            tasks.push(tokio::spawn(
+                async move { handler.gossip_state(runtime).await },
+            ));
+        }
+
+        {
+            let handler = self.clone();
+            tasks.push(tokio::spawn(async move { handler.log_metrics().await }));
+        }
+        {
+            let handler = self.clone();
+            tasks.push(tokio::spawn(async move { handler.compact_db().await }));
+        }
+        {
+            let runtime = runtime.clone();
+            let handler = self.clone();
+            tasks.push(tokio::spawn(async move {
+                handler.replay_pre_init(runtime).await
+            }));
+        }
+        tasks
+    }
+
+    // never goes backwards within a node, not even if the clock does. Once a millisecond's
+    // sequence is used up this spins until the next one.
+    fn next_snowflake(&self, node_id: &str) -> u64 {
+        let sequence_mask = (1 << SNOWFLAKE_SEQUENCE_BITS) - 1;
+        loop {
+            let now = (std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64)
+                .saturating_sub(SNOWFLAKE_EPOCH_MS);
+            let last = self.snowflake.load(Ordering::Acquire);
+            let next = if now > last >> SNOWFLAKE_SEQUENCE_BITS {
+                now << SNOWFLAKE_SEQUENCE_BITS
+            } else if last & sequence_mask < sequence_mask {
+                last + 1
+            } else {
+                std::hint::spin_loop();
+                continue;
+            };
+            if self
+                .snowflake
+                .compare_exchange(last, next, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                let millis = next >> SNOWFLAKE_SEQUENCE_BITS;
+                return (millis << (SNOWFLAKE_WORKER_BITS + SNOWFLAKE_SEQUENCE_BITS))
+                    | (snowflake_worker(node_id) << SNOWFLAKE_SEQUENCE_BITS)
+                    | (next & sequence_mask);
+            }
+        }
+    }
+
+    // replies to a state-changing request and keeps the reply for resends of it
+    async fn reply(&self, rt: &Runtime, req: &Message, resp: Response) -> Result<Option<()>> {
+        let resp = resp.into_body();
+        self.replies
+            .lock()
+            .unwrap()
+            .finish(&(req.src.clone(), req.body.msg_id), resp.clone());
+        rt.reply(req.clone(), resp).await.map(Some)
+    }
+
+    // the locks are held at once, see the order on `Handler`, so the parts agree with each
+    // other. The addressbook is copied first, it's only changed by init and topology messages.
+    pub fn snapshot(&self) -> HandlerSnapshot {
+        let addressbook = self.addressbook.to_map();
+        let tree_neighbours = self.tree_neighbours.lock().unwrap();
+        let outbox = self.outbox.lock().unwrap();
+        let pending = self.pending.lock().unwrap();
+        let seen = self.seen.read().unwrap();
+
+        let mut snapshot = HandlerSnapshot {
+            seen: seen.iter().copied().collect(),
+            addressbook: addressbook
+                .iter()
+                .map(|(node, peers)| {
+                    let mut peers: Vec<String> = peers.iter().cloned().collect();
+                    peers.sort();
+                    (node.clone(), peers)
+                })
+                .collect(),
+            tree_neighbours: tree_neighbours.iter().cloned().collect(),
+            outbox: outbox
+                .iter()
+                .map(|(node, queued)| (node.clone(), sorted_keys(queued)))
+                .collect(),
+            pending: pending
+                .iter()
+                .map(|(node, retries)| (node.clone(), sorted_keys(retries)))
+                .collect(),
+        };
+        snapshot.tree_neighbours.sort();
+        snapshot
+    }
+
+    fn rng(&self) -> std::sync::MutexGuard<'_, StdRng> {
+        self.rng.lock().unwrap()
+    }
+
+    // Messages can race the init message, e.g. a broadcast arriving before the db is open.
+    // They're held until init is done (see `hold_until_init`), and when too many are held the
+    // rest fail here with a temporarily-unavailable error (code 11), which `process` replies
+    // to the sender so it can retry, instead of treating it as a fatal node error.
+    fn db(&self) -> Result<&Db> {
+        self.db
+            .get()
+            .ok_or_else(|| Error::TemporarilyUnavailable.into())
+    }
+
+    // opens the db and loads what's stored in it. Messages held meanwhile are let through
+    // only after everything is loaded, or turned away if that failed.
+    async fn init_db(&self, node_id: &str) -> Result<()> {
+        let result = self.load_db(node_id).await;
+        let mut pre_init = self.pre_init.lock().unwrap();
+        pre_init.finished.get_or_insert(result.is_ok());
+        self.initialized.notify_one();
+        result
+    }
+
+    async fn load_db(&self, node_id: &str) -> Result<()> {
+        let db = self
+            .db
+            .get_or_try_init(|| async {
+                let dir = self.config.db_dir.clone().unwrap_or_default();
+                let db = Db::open_with_cache(
+                    dir.join(format!("{}.redb", node_id)),
+                    self.config.recover_db,
+                    self.config.db_cache_size,
+                )?
+                .with_durability(self.config.durability)
+                .with_write_retries(self.config.db_write_retries)
+                .with_timeout(self.config.db_timeout);
+                if db.recovered() && self.config.id_scheme == IdScheme::NodeCounter {
+                    warn!("Db was recreated, node-counter ids could repeat so none are handed out");
+                }
+                // restored before the db is visible, a generate could hand out ids from 0 otherwise
+                let id_limit = db.id_limit().await?;
+                *self.id_limit.lock().await = id_limit;
+                self.id_counter.store(id_limit, Ordering::Relaxed);
+                Ok::<_, DbError>(db)
+            })
+            .await
+            .map_err(db_error)?;
+
+        let values = db.seen_broadcast_values().await.map_err(db_error)?;
+        self.add_seen(values);
+        let blobs = db.broadcast_blobs().await.map_err(db_error)?;
+        {
+            let mut known = self.blobs.write().unwrap();
+            for bytes in blobs {
+                // written by this node, so one that doesn't parse means a damaged db
+                let blob = serde_json::from_slice(&bytes).map_err(|e| {
+                    warn!("Stored broadcast blob doesn't parse: {}", e);
+                    Error::Crash
+                })?;
+                known.insert(bytes, blob);
+            }
+        }
+
+        let topology = db.topology().await.map_err(db_error)?;
+        self.apply_topology(topology, node_id);
+        Ok(())
+    }
+
+    // opens the db under `Config::node_id` for running a node by hand, without maelstrom and
+    // so without an init. Does nothing when the runtime got its id from an init already.
+    pub async fn init_standalone(&self, rt: &Runtime) -> Result<()> {
+        let Some(node_id) = self.config.node_id.as_deref() else {
+            return Ok(());
+        };
+        if !rt.node_id().is_empty() {
+            return Ok(());
+        }
+        info!("No init yet, opening the db as {}", node_id);
+        *self.rng() = StdRng::seed_from_u64(rng_seed(self.config.seed, node_id));
+        self.init_db(node_id).await
+    }
+
+    // the runtime's node id, or `Config::node_id` while running standalone without an init
+    fn node_id<'a>(&'a self, rt: &'a Runtime) -> &'a str {
+        match rt.node_id() {
+            "" => self.config.node_id.as_deref().unwrap_or_default(),
+            node_id => node_id,
+        }
+    }
+
+    // None once init is done, otherwise whether req was held for `replay_pre_init` or has
+    // to be turned away, because too many are held already or init failed. Health is
+    // answered before init.
+    fn hold_until_init(&self, req: &Message) -> Option<bool> {
+        if matches!(req.get_type(), "init" | "health") {
+            return None;
+        }
+        let mut pre_init = self.pre_init.lock().unwrap();
+        match pre_init.finished {
+            Some(true) => None,
+            // the db may be open but only partly loaded, nothing can be served from it
+            Some(false) => Some(false),
+            None if pre_init.held.len() >= MAX_PRE_INIT_MESSAGES => Some(false),
+            None => {
+                pre_init.held.push_back(req.clone());
+                Some(true)
+            }
+        }
+    }
+
+    fn init_finished(&self) -> bool {
+        self.pre_init.lock().unwrap().finished == Some(true)
+    }
+
+    // runs the messages held before init through `process` in the order they arrived, once
+    // init is done. Their replies go out after init_ok. If init failed they're answered with
+    // a temporarily-unavailable error instead, so their senders retry.
+    async fn replay_pre_init(&self, rt: Runtime) {
+        self.initialized.notified().await;
+        let held = std::mem::take(&mut self.pre_init.lock().unwrap().held);
+        if !self.init_finished() {
+            warn!(
+                "Init failed, turning away {} messages held for it",
+                held.len()
+            );
+            for req in held {
+                if let Err(e) = reply_error(&rt, req, Error::TemporarilyUnavailable).await {
+                    warn!("Replying to a held message failed: {}", e);
+                }
+            }
+            return;
+        }
+        if !held.is_empty() {
+            info!("Replaying {} messages received before init", held.len());
+        }
+        for req in held {
+            let typ = req.body.typ.clone();
+            if let Err(e) = self.process(rt.clone(), req).await {
+                warn!("Replaying {:?} failed: {}", typ, e);
+            }
+        }
+    }
+
+    // next node-counter id, reserving a new block in the db before handing out the first id
+    // past the current one. A db started over after corruption has lost the reserved limit,
+    // counting again from 0 would reissue ids, so none are handed out then.
+    async fn next_counter_id(&self) -> Result<u64> {
+        if self.db()?.recovered() {
+            return Err(Error::Abort.into());
+        }
+        let id = self.id_counter.fetch_add(1, Ordering::Relaxed);
+        let mut limit = self.id_limit.lock().await;
+        if id >= *limit {
+            let next = id + ID_BLOCK;
+            self.db()?.set_id_limit(next).await.map_err(db_error)?;
+            *limit = next;
+        }
+        Ok(id)
+    }
+
+    fn apply_topology(&self, topology: Topology, node_id: &str) {
+        // held throughout, so concurrent topologies patch the tree in the order they applied
+        let mut spanning_tree = self.spanning_tree.lock().unwrap();
+        let applied_empty = topology.is_empty();
+        let graph = self.addressbook.set_topology(topology, node_id);
+        spanning_tree.update(topology_edges(&graph));
+        // maelstrom sometimes means to hand out a partial graph, so this only warns.
+        // init applies an empty topology before any has been sent, nothing to check then
+        let unreachable = unreachable_nodes(&graph);
+        if !applied_empty && !unreachable.is_empty() {
+            warn!(
+                "Topology is not connected, {:?} can't be reached from the rest, values won't converge",
+                unreachable
+            );
+        }
+        *self.tree_neighbours.lock().unwrap() = spanning_tree.neighbours(node_id);
+        *self.distances.lock().unwrap() = hop_distances(&graph, node_id);
+    }
+
+    // sorted snapshot of the nodes in the addressbook, never includes this node
+    pub fn known_peers(&self) -> Vec<String> {
+        self.addressbook.neighbours().to_vec()
+    }
+
+    // `known_peers` without the copy, cheap to call on every message
+    fn neighbours(&self) -> Arc<Vec<String>> {
+        self.addressbook.neighbours()
+    }
+
+    // stores one broadcast value, true if it is new. The in-memory set decides what's new,
+    // not the db: a write that timed out may still commit, and the client's retry then has
+    // to be forwarded even though the db already has the value. With optimistic acks the
+    // write is only queued.
+    async fn store_broadcast(&self, db: &Db, message: u64) -> Result<bool> {
+        if self.config.optimistic_acks {
+            db.queue_broadcast_id(message).map_err(db_error)?;
+        } else {
+            db.set_broadcast_id(message).await.map_err(db_error)?;
+        }
+        Ok(!self.add_seen([message]).is_empty())
+    }
+
+    // adds values to the in-memory set and its merkle summary, returns the ones that were new
+    fn add_seen(&self, values: impl IntoIterator<Item = u64>) -> Vec<u64> {
+        let mut seen = self.seen.write().unwrap();
+        let mut merkle = self.merkle.lock().unwrap();
+        let mut added = vec![];
+        for value in values {
+            if seen.insert(value) {
+                merkle.insert(value);
+                added.push(value);
+            }
+        }
+        added
+    }
+
+    // stores the values of one broadcast and returns the new ones, a single value takes the
+    // `store_broadcast` path
+    async fn store_broadcasts(&self, db: &Db, messages: Vec<u64>) -> Result<Vec<u64>> {
+        match messages[..] {
+            [message] => Ok(if self.store_broadcast(db, message).await? {
+                vec![message]
+            } else {
+                vec![]
+            }),
+            _ => self.merge_broadcast_values(messages).await,
+        }
+    }
+
+    // stores blob values and returns the new ones. serde_json keeps object keys sorted, so
+    // equal values serialize to the same bytes and dedupe.
+    async fn store_blobs(&self, db: &Db, blobs: Vec<Value>) -> Result<Vec<Value>> {
+        let mut new = vec![];
+        for blob in blobs {
+            let bytes = serde_json::to_vec(&blob)?;
+            if self.blobs.read().unwrap().contains_key(&bytes) {
+                continue;
+            }
+            if db
+                .set_broadcast_blob(bytes.clone())
+                .await
+                .map_err(db_error)?
+            {
+                new.push(blob.clone());
+            }
+            self.blobs.write().unwrap().insert(bytes, blob);
+        }
+        Ok(new)
+    }
+
+    // stores messages and returns the ones that weren't seen before. values already in the
+    // cache are filtered out first, so a mostly in-sync peer costs no db write at all
+    async fn merge_broadcast_values(&self, messages: Vec<u64>) -> Result<Vec<u64>> {
+        let unseen: Vec<u64> = {
+            let seen = self.seen.read().unwrap();
+            let unseen: BTreeSet<u64> = messages
+                .into_iter()
+                .filter(|message| !seen.contains(message))
+                .collect();
+            unseen.into_iter().collect()
+        };
+        if unseen.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // new is what wasn't seen, like in `store_broadcast`
+        self.db()?
+            .set_broadcast_ids(&unseen)
+            .await
+            .map_err(db_error)?;
+        Ok(self.add_seen(unseen))
+    }
+
+    // periodically reads a random peer's values and merges them, so values whose broadcast
+    // got lost (e.g. during a partition) still converge eventually
+    async fn anti_entropy(&self, rt: Runtime) {
+        let mut interval = tokio::time::interval(self.config.anti_entropy_interval);
+        let mut started = false;
+        loop {
+            interval.tick().await;
+
+            if self.db.get().is_none() {
+                continue;
+            }
+            if !started {
+                // every node gets init at about the same moment, offset the rounds per node so
+                // they don't all gossip at once
+                started = true;
+                tokio::time::sleep(start_delay(
+                    self.node_id(&rt),
+                    self.config.anti_entropy_interval,
+                ))
+                .await;
+                interval.reset();
+            }
+
+            // the broadcast senders probe open peers, anti-entropy waits until they're back
+            let peers: Vec<String> = self
+                .neighbours()
+                .iter()
+                .filter(|peer| !self.addressbook.breaker_is_open(peer))
+                .cloned()
+                .collect();
+            let peers = {
+                let distances = self.distances.lock().unwrap();
+                nearest_peers(peers, &distances, &mut *self.rng())
+            };
+            // ties are broken at random so nodes don't all pick the same peer
+            let peer = self
+                .addressbook
+                .stalest_peers(&peers)
+                .choose(&mut *self.rng())
+                .cloned();
+
+            if let Some(peer) = peer {
+                let synced = self.sync_with(&rt, &peer).await;
+                self.addressbook.record_call(&peer, synced.is_ok());
+                match synced {
+                    Ok(()) => self.addressbook.record_sync(&peer),
+                    Err(e) => warn!("Anti-entropy with {} failed: {}", peer, e),
+                }
+            }
+        }
+    }
+
+    // heuristic: the cluster counts as converged once every known peer has reported as many
+    // values as this node has, and that count held for `window`. Logged once per count, new
+    // values start a new wait.
+    async fn check_convergence(&self, rt: Runtime, window: Duration) {
+        let mut interval = tokio::time::interval(CONVERGENCE_CHECK_INTERVAL);
+        // count all peers agreed on, since when, and whether that was logged yet
+        let mut stable: Option<(usize, Instant, bool)> = None;
+        loop {
+            interval.tick().await;
+
+            if self.db.get().is_none() {
+                continue;
+            }
+            // health carries just the count, a read would ship every value
+            for peer in self.neighbours().iter() {
+                match call(&rt, peer.clone(), Request::Health {}).await {
+                    Ok(reply) => {
+                        let count = reply.body.extra.get("seen").and_then(Value::as_u64);
+                        self.addressbook
+                            .set_count(peer, count.unwrap_or_default() as usize);
+                    }
+                    Err(e) => {
+                        debug!("Asking {} for its count failed: {}", peer, e);
+                        self.addressbook.clear_count(peer);
+                    }
+                }
+            }
+
+            let count = self.seen.read().unwrap().len();
+            if !self.addressbook.all_counts_equal(count) {
+                stable = None;
+                continue;
+            }
+            match &mut stable {
+                Some((stable_count, since, logged)) if *stable_count == count => {
+                    if !*logged && since.elapsed() >= window {
+                        *logged = true;
+                        info!(
+                            "converged at {} values on {} nodes, stable for {:?}",
+                            count,
+                            self.neighbours().len() + 1,
+                            since.elapsed()
+                        );
+                    }
+                }
+                _ => stable = Some((count, Instant::now(), false)),
+            }
+        }
+    }
+
+    // sends our values and gets back the ones the peer has that we don't, so both sides
+    // converge in one round trip. that's two messages per round instead of four for a read
+    // each way, at the cost of always shipping our whole set even when the peer is up to date.
+    async fn sync_with(&self, rt: &Runtime, peer: &str) -> Result<()> {
+        let messages: Vec<u64> = self.seen.read().unwrap().iter().copied().collect();
+        if self.config.dry_run {
+            info!(
+                "Dry run, would sync {} values with {}",
+                messages.len(),
+                peer
+            );
+            return Ok(());
+        }
+        if self.config.merkle_sync {
+            return self.sync_merkle(rt, peer).await;
+        }
+        let resp = call(rt, peer.to_string(), Request::SyncValues { messages }).await?;
+
+        if let Request::SyncValuesOk { messages } = resp.body.as_obj()? {
+            self.merge_broadcast_values(messages).await?;
+        }
+        Ok(())
+    }
+
+    // walks down from the root to the leaves where our merkle summaries differ, one call per
+    // level. The last call carries our values in those leaves and brings back the peer's, so
+    // both sides end up with the union of them. Peers in sync answer the first call with no
+    // differences and nothing else is sent.
+    async fn sync_merkle(&self, rt: &Runtime, peer: &str) -> Result<()> {
+        let mut level = 0;
+        let mut indices = vec![0];
+        loop {
+            let nodes: Vec<(u32, u64)> = {
+                let merkle = self.merkle.lock().unwrap();
+                indices
+                    .iter()
+                    .filter_map(|index| Some((*index, merkle.hash(level, *index)?)))
+                    .collect()
+            };
+            let messages = if level == merkle::DEPTH {
+                values_in_leaves(&self.seen.read().unwrap(), &indices)
+            } else {
+                vec![]
+            };
+
+            let request = Request::SyncMerkle {
+                level,
+                nodes,
+                messages,
+            };
+            let resp = call(rt, peer.to_string(), request).await?;
+            let Request::SyncMerkleOk {
+                differing,
+                messages,
+            } = resp.body.as_obj()?
+            else {
+                return Err(format!("unexpected reply to sync_merkle: {:?}", resp.body.typ).into());
+            };
+
+            if level == merkle::DEPTH {
+                self.merge_broadcast_values(messages).await?;
+                return Ok(());
+            }
+            if differing.is_empty() {
+                return Ok(());
+            }
+            indices = differing
+                .into_iter()
+                .flat_map(MerkleSummary::children)
+                .collect();
+            level += 1;
+        }
+    }
+
+    // peers a new value received from src is passed on to
+    fn forward_targets(&self, rt: &Runtime, src: &str, seen_by: &[String]) -> Vec<String> {
+        let mut neighbours: Vec<String> = if self.config.spanning_tree {
+            let tree = self.tree_neighbours.lock().unwrap();
+            if rt.is_from_cluster(&src.to_string()) && !tree.contains(src) {
+                // arrived over a non-tree edge, keep it but don't forward
+                vec![]
+            } else {
+                tree.iter().filter(|node| *node != src).cloned().collect()
+            }
+        } else {
+            self.neighbours()
+                .iter()
+                .filter(|node| *node != src)
+                .cloned()
+                .collect()
+        };
+        neighbours.retain(|node| !seen_by.contains(node));
+        if neighbours.len() > self.config.fanout {
+            neighbours = neighbours
+                .choose_multiple(&mut *self.rng(), self.config.fanout)
+                .cloned()
+                .collect();
+        }
+        neighbours
+    }
+
+    // queues messages for the next batch to every forward target not in seen_by, hops is how
+    // many more relays the receivers may do. We go into seen_by for them.
+    fn forward(&self, rt: &Runtime, src: &str, messages: &[u64], hops: u8, seen_by: &[String]) {
+        let targets = self.forward_targets(rt, src, seen_by);
+
+        let mut seen_by: BTreeSet<String> = seen_by.iter().cloned().collect();
+        seen_by.insert(self.node_id(rt).to_string());
+        let relay = Relay { hops, seen_by };
+
+        let mut full = vec![];
+        {
+            let mut outbox = self.outbox.lock().unwrap();
+            for node in targets {
+                let queued = outbox.entry(node.clone()).or_default();
+                for message in messages {
+                    match queued.entry(*message) {
+                        Entry::Occupied(mut queued) => queued.get_mut().merge(relay.clone()),
+                        Entry::Vacant(queued) => {
+                            queued.insert(relay.clone());
+                        }
+                    }
+                }
+                // a full batch goes out now instead of waiting for the next flush
+                if queued.len() >= self.config.max_batch_size {
+                    full.extend(outbox.remove_entry(&node));
+                }
+            }
+        }
+        for (node, messages) in full {
+            self.send_batches(node, messages);
+        }
+    }
+
+    fn send_batches(&self, node: String, messages: HashMap<u64, Relay>) {
+        for (relay, messages) in group_by(messages) {
+            let seen_by = relay.seen_by.into_iter().collect();
+            self.gossip(node.clone(), messages, relay.hops, seen_by);
+        }
+    }
+
+    // blobs skip the outbox, pending retries and anti-entropy: they go out once, to every
+    // forward target
+    fn forward_blobs(
+        &self,
+        rt: &Runtime,
+        src: &str,
+        blobs: Vec<Value>,
+        hops: u8,
+        seen_by: &[String],
+    ) {
+        let mut seen: Vec<String> = seen_by.to_vec();
+        seen.push(self.node_id(rt).to_string());
+        for node in self.forward_targets(rt, src, seen_by) {
+            if self.config.dry_run {
+                info!("Dry run, would send blobs {:?} to {}", blobs, node);
+                continue;
+            }
+            let request = Request::BatchBroadcast {
+                messages: vec![],
+                hops: Some(hops),
+                seen_by: seen.clone(),
+                blobs: blobs.clone(),
+            };
+            if let Err(e) = send_with_id(rt, &node, &request) {
+                warn!("Failed to forward blobs to {}: {}", node, e);
+            }
+        }
+    }
+
+    async fn flush_batches(&self) {
+        let mut interval = tokio::time::interval(self.config.max_batch_delay);
+        loop {
+            interval.tick().await;
+
+            let outbox = std::mem::take(&mut *self.outbox.lock().unwrap());
+            for (node, messages) in outbox {
+                self.send_batches(node, messages);
+            }
+        }
+    }
+
+    // last chance before exiting: sends everything still buffered or unacked once more, without
+    // waiting for acks, and lets the db writer commit what it has queued
+    pub async fn shutdown(&self, rt: &Runtime) {
+        let mut unsent: HashMap<String, HashMap<u64, u8>> =
+            std::mem::take(&mut *self.outbox.lock().unwrap())
+                .into_iter()
+                .map(|(node, messages)| {
+                    let hops = messages.into_iter().map(|(m, relay)| (m, relay.hops));
+                    (node, hops.collect())
+                })
+                .collect();
+        for (node, pending) in std::mem::take(&mut *self.pending.lock().unwrap()) {
+            let unsent = unsent.entry(node).or_default();
+            for (message, retry) in pending {
+                let hops = unsent.entry(message).or_default();
+                *hops = (*hops).max(retry.hops);
+            }
+        }
+
+        for (node, messages) in unsent {
+            for (hops, messages) in group_by(messages) {
+                if self.config.dry_run {
+                    info!(
+                        "Dry run, would flush {:?} to {} with {} hops",
+                        messages, node, hops
+                    );
+                    continue;
+                }
+                let request = Request::BatchBroadcast {
+                    messages,
+                    hops: Some(hops),
+                    seen_by: vec![],
+                    blobs: vec![],
+                };
+                if let Err(e) = rt.send(node.clone(), request).await {
+                    warn!("Failed to flush broadcasts to {}: {}", node, e);
+                }
+            }
+        }
+
+        if let Some(db) = self.db.get() {
+            if let Err(e) = db.flush().await {
+                warn!("Failed to flush db writes: {}", e);
+            }
+        }
+    }
+
+    // queues messages to node as one batch and keeps them pending until the reply comes back.
+    // The runtime matches the reply to this exact call by msg_id, so it acks this batch only.
+    fn gossip(&self, node: String, messages: Vec<u64>, hops: u8, seen_by: Vec<String>) {
+        // nothing goes pending either, there is no ack coming
+        if self.config.dry_run {
+            info!(
+                "Dry run, would send {:?} to {} with {} hops",
+                messages, node, hops
+            );
+            return;
+        }
+        let base = self.config.retry_interval;
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let mut rng = self.rng();
+            let pending = pending.entry(node.clone()).or_default();
+            for message in &messages {
+                pending
+                    .entry(*message)
+                    .and_modify(|retry| {
+                        *retry = retry.retried(base, &mut *rng);
+                        retry.hops = retry.hops.max(hops);
+                    })
+                    .or_insert_with(|| PendingRetry::new(base, hops));
+            }
+            let dropped = drop_oldest(pending, MAX_PENDING_PER_PEER);
+            if dropped > 0 {
+                self.metrics
+                    .pending_dropped
+                    .fetch_add(dropped as u64, Ordering::Relaxed);
+                debug!(
+                    "Dropped {} pending values for {} over the cap",
+                    dropped, node
+                );
+            }
+        }
+
+        Metrics::incr(&self.metrics.broadcasts_forwarded);
+
+        let _ = self.outgoing.send(Outgoing {
+            node,
+            messages,
+            hops,
+            seen_by,
+        });
+    }
+
+    // one of the `Config::senders` tasks, sends queued batches one at a time
+    async fn send_gossip(&self, rt: Runtime) {
+        let base = self.config.retry_interval;
+        loop {
+            let Some(Outgoing {
+                node,
+                messages,
+                hops,
+                seen_by,
+            }) = self.outgoing_rx.lock().await.recv().await
+            else {
+                return;
+            };
+
+            // skipped values stay pending, `retry_unacked` queues them again
+            if !self.addressbook.breaker_allows(&node) {
+                continue;
+            }
+
+            let request = Request::BatchBroadcast {
+                messages: messages.clone(),
+                hops: Some(hops),
+                seen_by,
+                blobs: vec![],
+            };
+            let result = call(&rt, node.clone(), request).await;
+            self.addressbook.record_call(&node, result.is_ok());
+            match result {
+                Ok(reply) => {
+                    Metrics::incr(&self.metrics.acks_received);
+                    // only the values the ack names are cleared, the rest stay pending
+                    let acked = match reply.body.as_obj() {
+                        Ok(Request::BatchBroadcastOk { messages }) => messages,
+                        _ => vec![],
+                    };
+                    clear_acked(&self.pending, &node, &acked, base);
+                }
+                // the values went into pending before sending, `retry_unacked` resends them
+                Err(e) => debug!(
+                    "Broadcast of {} values to {} failed: {}",
+                    messages.len(),
+                    node,
+                    e
+                ),
+            }
+        }
+    }
+
+    async fn log_metrics(&self) {
+        let mut interval = tokio::time::interval(METRICS_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let metrics = &self.metrics;
+            let pending: usize = self
+                .pending
+                .lock()
+                .unwrap()
+                .values()
+                .map(HashMap::len)
+                .sum();
+            info!(
+                "Metrics: broadcasts received {}, broadcasts forwarded {}, reads served {}, acks received {}, pending {}, pending dropped {}",
+                metrics.broadcasts_received.load(Ordering::Relaxed),
+                metrics.broadcasts_forwarded.load(Ordering::Relaxed),
+                metrics.reads_served.load(Ordering::Relaxed),
+                metrics.acks_received.load(Ordering::Relaxed),
+                pending,
+                metrics.pending_dropped.load(Ordering::Relaxed),
+            );
+            let unhandled = self.unhandled.lock().unwrap().summary();
+            if !unhandled.is_empty() {
+                info!("Repeats of unhandled messages not logged: {:?}", unhandled);
+            }
+            if let Some(db) = self.db.get() {
+                let stats = db.stats();
+                info!(
+                    "Db stats: set_broadcast_id {}; seen_broadcast_values {}",
+                    stats.set_broadcast_id, stats.seen_broadcast_values
+                );
+            }
+        }
+    }
+
+    // shrinks the redb file once it has been idle for compact_idle, logging its size before
+    // and after. That's once per idle stretch, a db that keeps being written isn't compacted.
+    async fn compact_db(&self) {
+        let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+        let mut compacted_at: Option<std::time::Instant> = None;
+        loop {
+            interval.tick().await;
+
+            let Some(db) = self.db.get() else {
+                continue;
+            };
+            let now = std::time::Instant::now();
+            if !compaction_due(db.last_write(), compacted_at, self.config.compact_idle, now) {
+                continue;
+            }
+            compacted_at = Some(now);
+            let before = db.file_size().unwrap_or_default();
+            match db.compact().await {
+                Ok(true) => info!(
+                    "Compacted db: {} -> {} bytes",
+                    before,
+                    db.file_size().unwrap_or_default()
+                ),
+                Ok(false) => debug!("Db compaction had nothing to reclaim ({} bytes)", before),
+                Err(e) => warn!("Failed to compact db: {}", e),
+            }
+        }
+    }
+
+    // resends unacked broadcasts whose backoff has expired until the peer acks them
+    async fn retry_unacked(&self) {
+        let mut interval = tokio::time::interval(self.config.retry_interval);
+        loop {
+            interval.tick().await;
+
+            let now = Instant::now();
+            let pending: Vec<(String, HashMap<u64, u8>)> = {
+                let pending = self.pending.lock().unwrap();
+                pending
+                    .iter()
+                    .map(|(node, messages)| {
+                        let due = messages
+                            .iter()
+                            .filter(|(_, retry)| retry.next_retry <= now)
+                            .map(|(message, retry)| (*message, retry.hops))
+                            .collect();
+                        (node.clone(), due)
+                    })
+                    .collect()
+            };
+
+            for (node, messages) in pending {
+                // pending only remembers hops, retries go out without seen_by
+                for (hops, messages) in group_by(messages) {
+                    self.gossip(node.clone(), messages, hops, vec![]);
+                }
+            }
+        }
+    }
+
+    // pushes the whole counter or g-set state to every peer, they merge it into their own
+    async fn gossip_state(&self, rt: Runtime) {
+        let mut interval = tokio::time::interval(self.config.gossip_interval);
+        loop {
+            interval.tick().await;
+
+            let Some(db) = self.db.get() else {
+                continue;
+            };
+            let request = match self.state_request(db).await {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Failed to read the state to gossip: {}", e);
+                    continue;
+                }
+            };
+
+            for node in self.neighbours().iter() {
+                if let Err(e) = send_with_id(&rt, node, &request) {
+                    warn!("Failed to gossip state to {}: {}", node, e);
+                }
+            }
+        }
+    }
+
+    // None when there's nothing worth sending
+    async fn state_request(&self, db: &Db) -> std::result::Result<Option<Request>, DbError> {
+        if self.config.workload == WorkloadKind::GSet {
+            let set = db.g_set().await?;
+            if set.is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some(Request::Elements {
+                elements: set.into_elements(),
+            }));
+        }
+        let counter = db.pn_counter().await?;
+        Ok(Some(Request::Counters {
+            counters: counter.increments.into_sums(),
+            decrements: counter.decrements.into_sums(),
+        }))
+    }
+
+    // value of key in the lin-kv service, None if it was never written
+    pub async fn lin_kv_read(&self, rt: &Runtime, key: impl Into<Value>) -> Result<Option<Value>> {
+        let request = KvRequest::Read { key: key.into() };
+        match call_service(rt, LIN_KV, request).await {
+            Ok(reply) => Ok(reply.body.extra.get("value").cloned()),
+            Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::KeyDoesNotExist)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn lin_kv_write(
+        &self,
+        rt: &Runtime,
+        key: impl Into<Value>,
+        value: impl Into<Value>,
+    ) -> Result<()> {
+        let request = KvRequest::Write {
+            key: key.into(),
+            value: value.into(),
+        };
+        call_service(rt, LIN_KV, request).await?;
+        Ok(())
+    }
+
+    // fails with Error::PreconditionFailed if key doesn't hold `from`, and with
+    // Error::KeyDoesNotExist if it doesn't exist and `create` is false
+    pub async fn lin_kv_cas(
+        &self,
+        rt: &Runtime,
+        key: impl Into<Value>,
+        from: impl Into<Value>,
+        to: impl Into<Value>,
+        create: bool,
+    ) -> Result<()> {
+        let request = KvRequest::Cas {
+            key: key.into(),
+            from: from.into(),
+            to: to.into(),
+            create_if_not_exists: create,
+        };
+        call_service(rt, LIN_KV, request).await?;
+        Ok(())
+    }
+}
+
+// maelstrom node ids are "n<index>", the index keeps workers distinct. Other ids are hashed.
+fn snowflake_worker(node_id: &str) -> u64 {
+    let worker = node_id
+        .strip_prefix('n')
+        .and_then(|index| index.parse().ok())
+        .unwrap_or_else(|| {
+            let mut hasher = DefaultHasher::new();
+            node_id.hash(&mut hasher);
+            hasher.finish()
+        });
+    worker & ((1 << SNOWFLAKE_WORKER_BITS) - 1)
+}
+
+// mostly just the peers closest to this node, see NEAR_SYNC_SHARE. Peers the topology doesn't
+// connect to us count as farthest.
+fn nearest_peers(
+    peers: Vec<String>,
+    distances: &HashMap<String, usize>,
+    rng: &mut impl Rng,
+) -> Vec<String> {
+    if !rng.gen_bool(NEAR_SYNC_SHARE) {
+        return peers;
+    }
+    let distance = |peer: &String| distances.get(peer).copied().unwrap_or(usize::MAX);
+    let Some(nearest) = peers.iter().map(distance).min() else {
+        return peers;
+    };
+    peers
+        .into_iter()
+        .filter(|peer| distance(peer) == nearest)
+        .collect()
+}
+
+// the values of seen that go to one of the given merkle leaves
+fn values_in_leaves(seen: &BTreeSet<u64>, leaves: &[u32]) -> Vec<u64> {
+    let leaves: HashSet<u32> = leaves.iter().copied().collect();
+    seen.iter()
+        .copied()
+        .filter(|value| leaves.contains(&MerkleSummary::leaf(*value)))
+        .collect()
+}
+
+fn sorted_keys<V>(map: &HashMap<u64, V>) -> Vec<u64> {
+    let mut keys: Vec<u64> = map.keys().copied().collect();
+    keys.sort_unstable();
+    keys
+}
+
+fn rng_seed(seed: Option<u64>, node_id: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (seed, node_id).hash(&mut hasher);
+    hasher.finish()
+}
+
+// somewhere within one period, derived from the node id so a node waits the same on every run
+fn start_delay(node_id: &str, period: Duration) -> Duration {
+    let mut hasher = DefaultHasher::new();
+    node_id.hash(&mut hasher);
+    period.mul_f64((hasher.finish() % 1000) as f64 / 1000.0)
+}
+
+// base * 2^attempts capped at MAX_RETRY_INTERVAL, with the upper half jittered so peers
+// coming back from a partition don't get every node's retries at the same moment
+fn backoff(base: Duration, attempts: u32, rng: &mut impl Rng) -> Duration {
+    let delay = base
+        .saturating_mul(1 << attempts.min(16))
+        .min(MAX_RETRY_INTERVAL);
+    let half = delay / 2;
+    half + half.mul_f64(rng.gen::<f64>())
+}
+
+// one batch carries a single hop count and seen_by, so values are sent in a batch per key
+fn group_by<K: Ord>(messages: HashMap<u64, K>) -> BTreeMap<K, Vec<u64>> {
+    let mut groups: BTreeMap<K, Vec<u64>> = BTreeMap::new();
+    for (message, key) in messages {
+        groups.entry(key).or_default().push(message);
+    }
+    groups
+}
+
+// trims a peer's pending values down to cap by dropping the ones queued first, returns how
+// many were dropped. anti-entropy delivers them eventually.
+fn drop_oldest(pending: &mut HashMap<u64, PendingRetry>, cap: usize) -> usize {
+    if pending.len() <= cap {
+        return 0;
+    }
+    let excess = pending.len() - cap;
+    let mut by_age: Vec<(Instant, u64)> = pending
+        .iter()
+        .map(|(message, retry)| (retry.queued_at, *message))
+        .collect();
+    by_age.sort_unstable();
+    for (_, message) in by_age.into_iter().take(excess) {
+        pending.remove(&message);
+    }
+    excess
+}
+
+// drops acked values; the peer is evidently reachable again, so the rest of its backlog
+// starts over from the base interval instead of waiting out a long backoff
+fn clear_acked(
+    pending: &Mutex<HashMap<String, HashMap<u64, PendingRetry>>>,
+    node: &str,
+    messages: &[u64],
+    base: Duration,
+) {
+    if let Some(pending) = pending.lock().unwrap().get_mut(node) {
+        for message in messages {
+            pending.remove(message);
+        }
+        let next_retry = Instant::now() + base;
+        for retry in pending.values_mut() {
+            if retry.next_retry > next_retry {
+                retry.attempts = 0;
+                retry.next_retry = next_retry;
+            }
+        }
+    }
+}
+
+// true once nothing was written for idle, unless the db was compacted since the last write
+fn compaction_due(
+    last_write: std::time::Instant,
+    compacted_at: Option<std::time::Instant>,
+    idle: Duration,
+    now: std::time::Instant,
+) -> bool {
+    now.duration_since(last_write) >= idle && compacted_at.is_none_or(|at| at < last_write)
+}
+
+// requests that change state, applying a resent copy again would e.g. count an add twice
+fn is_deduplicated(request: &Request) -> bool {
+    matches!(
+        request,
+        Request::Broadcast { .. }
+            | Request::Add { .. }
+            | Request::Send { .. }
+            | Request::CommitOffsets { .. }
+            | Request::Txn { .. }
+            | Request::Write { .. }
+            | Request::Cas { .. }
+    )
+}
+
+// body fields the parsed request doesn't have, serde drops those silently so a typo in a
+// field name would otherwise only show up as a missing optional value
+fn unknown_fields(body: &MessageBody, request: &Request) -> Vec<String> {
+    let known = request.fields();
+    body.extra
+        .keys()
+        .filter(|field| !known.contains(&field.as_str()))
+        .cloned()
+        .collect()
+}
+
+fn edge(a: &str, b: &str) -> Edge {
+    if a < b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+fn topology_edges(topology: &HashMap<String, HashSet<String>>) -> BTreeSet<Edge> {
+    topology
+        .iter()
+        .flat_map(|(node, peers)| peers.iter().map(move |peer| edge(node, peer)))
+        .filter(|(a, b)| a != b)
+        .collect()
+}
+
+// breadth-first tree over edges from the smallest node id to every node reachable from it.
+// Peers are visited in order so every node builds the same tree from the same edges.
+fn build_tree(edges: &BTreeSet<Edge>) -> BTreeSet<Edge> {
+    let mut adjacency: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for (a, b) in edges {
+        adjacency.entry(a).or_default().insert(b);
+        adjacency.entry(b).or_default().insert(a);
+    }
+
+    let mut tree = BTreeSet::new();
+    let mut visited: BTreeSet<&str> = adjacency.keys().next().copied().into_iter().collect();
+    let mut queue: VecDeque<&str> = visited.iter().copied().collect();
+    while let Some(node) = queue.pop_front() {
+        for peer in adjacency.get(node).into_iter().flatten() {
+            if visited.insert(peer) {
+                tree.insert(edge(node, peer));
+                queue.push_back(peer);
+            }
+        }
+    }
+
+    tree
+}
+
+// nodes not connected to the lexicographically smallest one, edges count in both directions.
+// Empty when the graph is connected.
+fn unreachable_nodes(topology: &HashMap<String, HashSet<String>>) -> BTreeSet<String> {
+    let mut edges: HashMap<&String, HashSet<&String>> = HashMap::new();
+    for (node, peers) in topology {
+        edges.entry(node).or_default();
+        for peer in peers {
+            edges.entry(node).or_default().insert(peer);
+            edges.entry(peer).or_default().insert(node);
+        }
+    }
+    let Some(root) = edges.keys().min().copied() else {
+        return BTreeSet::new();
+    };
+
+    let mut visited = HashSet::from([root]);
+    let mut queue = VecDeque::from([root]);
+    while let Some(node) = queue.pop_front() {
+        for peer in &edges[node] {
+            if visited.insert(*peer) {
+                queue.push_back(*peer);
+            }
+        }
+    }
+
+    edges
+        .into_keys()
+        .filter(|node| !visited.contains(node))
+        .cloned()
+        .collect()
+}
+
+// breadth-first over the undirected graph, nodes that can't be reached from `from` are left out
+fn hop_distances(graph: &HashMap<String, HashSet<String>>, from: &str) -> HashMap<String, usize> {
+    let mut edges: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for (node, peers) in graph {
+        for peer in peers {
+            edges.entry(node).or_default().insert(peer);
+            edges.entry(peer).or_default().insert(node);
+        }
+    }
+
+    let mut distances = HashMap::from([(from.to_string(), 0)]);
+    let mut queue = VecDeque::from([(from, 0)]);
+    while let Some((node, distance)) = queue.pop_front() {
+        for peer in edges.get(node).into_iter().flatten() {
+            if !distances.contains_key(*peer) {
+                distances.insert(peer.to_string(), distance + 1);
+                queue.push_back((peer, distance + 1));
+            }
+        }
+    }
+    distances
+}
+
+// txn ops come in as ["r", key, null] or ["w", key, value]
+fn parse_txn_op(op: &[Value; 3]) -> Option<TxnOp> {
+    let key = op[1].as_u64()?;
+    match op[0].as_str()? {
+        "r" => Some(TxnOp::Read(key, None)),
+        "w" => Some(TxnOp::Write(key, op[2].as_u64()?)),
+        _ => None,
+    }
+}
+
+fn txn_op_to_json(op: TxnOp) -> [Value; 3] {
+    match op {
+        TxnOp::Read(key, value) => [json!("r"), json!(key), json!(value)],
+        TxnOp::Write(key, value) => [json!("w"), json!(key), json!(value)],
+    }
+}
+
+// rpc to another node, failing if it doesn't reply within RPC_TIMEOUT. Besides the timeout it
+// fails when the request can't be serialized or written to stdout, and when the peer replies
+// with an error body, e.g. temporarily-unavailable from a node that hasn't got init yet.
+// The runtime picks the msg_id without handing it out, it's only known from the reply's
+// in_reply_to: the span gets it then, and the reply is logged with it.
+async fn call(rt: &Runtime, to: String, request: Request) -> Result<Message> {
+    let span = info_span!("rpc", dest = %to, msg_id = tracing::field::Empty);
+    async {
+        debug!("Calling {} with {:?}", to, request);
+        let call = rt.rpc(to, request).await?;
+        let reply = tokio::time::timeout(RPC_TIMEOUT, call).await??;
+        tracing::Span::current().record("msg_id", reply.body.in_reply_to);
+        debug!(
+            "Reply {:?} to msg {}",
+            reply.body.typ, reply.body.in_reply_to
+        );
+        Ok(reply)
+    }
+    .instrument(span)
+    .await
+}
+
+// send_async with a msg_id, which the runtime leaves out of one-way messages. Returns it so a
+// message can be told apart in the logs, e.g. which incoming broadcast a forward came from.
+fn send_with_id(rt: &Runtime, to: &str, request: &Request) -> Result<u64> {
+    let msg_id = rt.next_msg_id();
+    let Value::Object(mut body) = serde_json::to_value(request)? else {
+        return Err("request doesn't serialize to an object".into());
+    };
+    body.insert("msg_id".to_string(), msg_id.into());
+    debug!("Sending msg {} to {}: {:?}", msg_id, to, request);
+    rt.send_async(to, body)?;
+    Ok(msg_id)
+}
+
+// rpc to one of maelstrom's services, e.g. LIN_KV. A reply that doesn't come within RPC_TIMEOUT
+// fails with Error::Timeout: the request may still have been applied, so callers must not
+// assume either outcome. Error replies come back as the matching maelstrom Error.
+async fn call_service(rt: &Runtime, service: &str, request: KvRequest) -> Result<Message> {
+    let span = info_span!("rpc", dest = service);
+    async move {
+        let call = rt.rpc(service, request).await?;
+        match tokio::time::timeout(RPC_TIMEOUT, call).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Timeout.into()),
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+// db failures are usually transient (e.g. a busy disk), report them as retriable
+// instead of failing the whole node. A build without storage won't ever succeed though.
+fn db_error(e: DbError) -> Error {
+    warn!("db operation failed: {}", e);
+    match e {
+        #[cfg(not(feature = "persistence"))]
+        DbError::Disabled => Error::NotSupported("storage-backed".to_string()),
+        // the operation may still complete, so it can't be reported as definitely failed
+        DbError::Timeout(_) => Error::Timeout,
+        // retrying can only overflow again
+        DbError::CounterOverflow { .. } => Error::Abort,
+        _ => Error::TemporarilyUnavailable,
+    }
+}
+
+async fn reply_error(rt: &Runtime, req: Message, err: Error) -> Result<()> {
+    warn!("Replying {} to {:?}", err, req.body.typ);
+    rt.reply(req, ErrorMessageBody::from_error(err)).await
+}
+
+#[async_trait]
+impl Node for Handler {
+    // everything logged while handling req, db writes and rpcs included, is tagged with it
+    async fn process(&self, rt: Runtime, req: Message) -> Result<()> {
+        let span = info_span!(
+            "request",
+            typ = %req.body.typ,
+            src = %req.src,
+            msg_id = req.body.msg_id
+        );
+        self.process_request(rt, req).instrument(span).await
+    }
+}
+
+impl Handler {
+    async fn process_request(&self, rt: Runtime, req: Message) -> Result<()> {
+        match self.hold_until_init(&req) {
+            Some(true) => return Ok(()),
+            Some(false) => return reply_error(&rt, req, Error::TemporarilyUnavailable).await,
+            None => {}
+        }
+        match self.handle(rt.clone(), req.clone()).await {
+            // maelstrom errors are meant for the client, anything else is a node failure.
+            // `done` already replied to unsupported messages itself.
+            Err(e) => match e.downcast::<Error>() {
+                Ok(err) if !matches!(*err, Error::NotSupported(_)) => {
+                    reply_error(&rt, req, *err).await
+                }
+                Ok(err) => Err(err),
+                Err(e) => Err(e),
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+impl Handler {
+    fn workload(&self) -> &'static dyn Workload {
+        match self.config.workload {
+            WorkloadKind::Broadcast => &BroadcastWorkload,
+            WorkloadKind::GCounter => &CounterWorkload { pn: false },
+            WorkloadKind::PnCounter => &CounterWorkload { pn: true },
+            WorkloadKind::Kafka => &KafkaWorkload,
+            WorkloadKind::Txn => &TxnWorkload,
+            WorkloadKind::GSet => &GSetWorkload,
+            WorkloadKind::LinKv => &LinKvWorkload,
+        }
+    }
+
+    async fn handle(&self, rt: Runtime, req: Message) -> Result<()> {
+        let msg: Result<Request> = req.body.as_obj();
+
+        // only worth looking for when it's rejected or logged
+        let check_fields = self.config.strict || enabled!(Level::DEBUG);
+        if let (true, Ok(request)) = (check_fields, &msg) {
+            let unknown = unknown_fields(&req.body, request);
+            if !unknown.is_empty() {
+                warn!(
+                    "Message of type {:?} has unexpected fields {:?}",
+                    req.body.typ, unknown
+                );
+                if self.config.strict {
+                    return Err(Error::MalformedRequest.into());
+                }
+            }
+        }
+
+        match msg {
+            Ok(Request::Init { node_id, node_ids }) => {
+                // init has been proceeded by the runtime
+                // we now know the node_id
+                if rt.node_id().is_empty() {
+                    return Err(Error::MalformedRequest.into());
+                }
+                for peer in node_ids {
+                    self.addressbook.add_peer(&node_id, &peer);
+                }
+
+                *self.rng() = StdRng::seed_from_u64(rng_seed(self.config.seed, &node_id));
+                self.init_db(&node_id).await?;
+            }
+            // challenge #1
+            Ok(Request::Echo { echo }) => {
+                // only the echo field, rt.reply fills in in_reply_to
+                let resp = Response::ok("echo_ok").with("echo", echo);
+                return rt.reply(req, resp).await;
+            }
+
+            Ok(Request::DumpState {}) => {
+                let state = self.db()?.export().await.map_err(db_error)?;
+                let resp = Response::ok("dump_state_ok")
+                    .with("state", state)
+                    .with("memory", serde_json::to_value(self.snapshot())?);
+                return rt.reply(req, resp).await;
+            }
+
+            // values already in flight to peers are dropped too, they'd only come back
+            Ok(Request::Reset {}) => {
+                self.db()?.clear().await.map_err(db_error)?;
+                self.outbox.lock().unwrap().clear();
+                self.pending.lock().unwrap().clear();
+                {
+                    let mut seen = self.seen.write().unwrap();
+                    seen.clear();
+                    *self.merkle.lock().unwrap() = MerkleSummary::default();
+                }
+                self.blobs.write().unwrap().clear();
+                info!("Reset, every broadcast value is forgotten");
+                return rt.reply(req, Response::ok("reset_ok")).await;
+            }
+
+            Ok(Request::Health {}) => {
+                let resp = Response::ok("health_ok")
+                    .with("initialized", self.init_finished())
+                    .with("seen", self.seen.read().unwrap().len())
+                    .with("peers", self.neighbours().len());
+                return rt.reply(req, resp).await;
+            }
+
+            // challenge #2 - unique id
+            Ok(Request::Generate {}) => {
+                let id = match self.config.id_scheme {
+                    // never from the seeded rng, a restarted node would hand out the same ids
+                    IdScheme::Uuid => uuid::Uuid::new_v4().to_string(),
+                    IdScheme::NodeCounter => {
+                        format!("{}-{}", self.node_id(&rt), self.next_counter_id().await?)
+                    }
+                    IdScheme::Snowflake => self.next_snowflake(self.node_id(&rt)).to_string(),
+                };
+                let resp = Response::ok("generate_ok").with("id", id);
+                return rt.reply(req, resp).await;
+            }
+
+            Ok(Request::Topology { topology }) => {
+                if !topology.is_symmetric() {
+                    warn!(
+                        "Topology matrix isn't symmetric, its one-way edges are kept as they are"
+                    );
+                }
+                let Some(topology) = topology.into_list(rt.nodes()) else {
+                    warn!(
+                        "Topology matrix isn't square with a row per node of {:?}",
+                        rt.nodes()
+                    );
+                    return Err(Error::MalformedRequest.into());
+                };
+
+                // nodes init didn't announce usually mean a misconfigured test, keep going but say so
+                let unknown: BTreeSet<&String> = topology
+                    .iter()
+                    .flat_map(|(node, peers)| std::iter::once(node).chain(peers))
+                    .filter(|node| !rt.nodes().contains(node))
+                    .collect();
+                if !unknown.is_empty() {
+                    warn!("Topology references nodes missing from init: {:?}", unknown);
+                }
+
+                self.db()?
+                    .set_topology(topology.clone())
+                    .await
+                    .map_err(db_error)?;
+                self.apply_topology(topology, self.node_id(&rt));
+                info!("Topology applied, known peers: {:?}", self.known_peers());
+
+                let resp = Response::ok("topology_ok");
+                return rt.reply(req, resp).await;
+            }
+
+            Ok(request) => {
+                let key = (is_deduplicated(&request) && req.body.msg_id > 0)
+                    .then(|| (req.src.clone(), req.body.msg_id));
+                if let Some(key) = &key {
+                    let cached = self.replies.lock().unwrap().begin(key.clone());
+                    match cached {
+                        Some(Some(reply)) => {
+                            debug!("Replaying the reply to resent {:?}", key);
+                            return rt.reply(req, reply).await;
+                        }
+                        // the first copy replies once it's done
+                        Some(None) => return Ok(()),
+                        None => {}
+                    }
+                }
+
+                let handled = self.workload().handle(self, &rt, &req, request).await;
+                if let Some(key) = &key {
+                    if !matches!(handled, Ok(Some(()))) {
+                        self.replies.lock().unwrap().abandon(key);
+                    }
+                }
+                if handled?.is_some() {
+                    return Ok(());
+                }
+                if self.unhandled.lock().unwrap().first(&req.body.typ) {
+                    info!(
+                        "Message of type {:?} not handled by the {:?} workload: {:?}",
+                        req.body.typ, self.config.workload, req.body
+                    );
+                }
+            }
+
+            Err(e) => {
+                if self.unhandled.lock().unwrap().first(&req.body.typ) {
+                    info!(
+                        "Message of type {:?} failed to match: {}: {:?}",
+                        req.body.typ, e, req.body
+                    );
+                }
+            }
+        };
+
+        done(rt, req)
+    }
+}
+
+// each challenge handles its own requests, `Handler` keeps the shared state and the
+// requests every workload needs (init, echo, generate, topology)
+#[async_trait]
+trait Workload: Send + Sync {
+    // Ok(None) means the request isn't part of this workload
+    async fn handle(
+        &self,
+        node: &Handler,
+        rt: &Runtime,
+        req: &Message,
+        request: Request,
+    ) -> Result<Option<()>>;
+}
+
+// challenge #3 - broadcast
+struct BroadcastWorkload;
+
+#[async_trait]
+impl Workload for BroadcastWorkload {
+    async fn handle(
+        &self,
+        node: &Handler,
+        rt: &Runtime,
+        req: &Message,
+        request: Request,
+    ) -> Result<Option<()>> {
+        match request {
+            Request::Broadcast {
+                values,
+                hops,
+                seen_by,
+            } => {
+                Metrics::incr(&node.metrics.broadcasts_received);
+                let db = node.db()?;
+                let (messages, blobs) = values.into_parts();
+
+                // single-node broadcast, there's nobody to forward to so just store and ack
+                if node.neighbours().is_empty() {
+                    node.store_broadcasts(db, messages).await?;
+                    node.store_blobs(db, blobs).await?;
+
+                    let resp = Response::ok("broadcast_ok");
+                    return node.reply(rt, req, resp).await;
+                }
+
+                let new = node.store_broadcasts(db, messages).await?;
+                let new_blobs = node.store_blobs(db, blobs).await?;
+
+                if enabled!(Level::DEBUG) {
+                    match db.count().await {
+                        Ok(count) => debug!("Stored broadcast {:?}, {} values seen", new, count),
+                        Err(e) => warn!("Failed to count broadcast values: {}", e),
+                    }
+                }
+
+                // only gossip values we haven't seen before, otherwise they bounce around forever.
+                // a client broadcast starts with max_hops relays, at zero we keep the value to ourselves
+                let hops = hops.unwrap_or(node.config.max_hops);
+                if !new.is_empty() && hops > 0 {
+                    node.forward(rt, &req.src, &new, hops - 1, &seen_by);
+                }
+                if !new_blobs.is_empty() && hops > 0 {
+                    node.forward_blobs(rt, &req.src, new_blobs, hops - 1, &seen_by);
+                }
+
+                let resp = Response::ok("broadcast_ok");
+                return node.reply(rt, req, resp).await;
+            }
+
+            Request::BatchBroadcast {
+                messages,
+                hops,
+                seen_by,
+                blobs,
+            } => {
+                Metrics::incr(&node.metrics.broadcasts_received);
+                // named in the ack, the sender clears exactly these from its pending set
+                let acked = messages.clone();
+                let inserted = node.merge_broadcast_values(messages).await?;
+                let new_blobs = node.store_blobs(node.db()?, blobs).await?;
+                let hops = hops.unwrap_or(node.config.max_hops);
+                if !inserted.is_empty() && hops > 0 {
+                    node.forward(rt, &req.src, &inserted, hops - 1, &seen_by);
+                }
+                if !new_blobs.is_empty() && hops > 0 {
+                    node.forward_blobs(rt, &req.src, new_blobs, hops - 1, &seen_by);
+                }
+
+                let resp = Response::ok("batch_broadcast_ok").with("messages", acked);
+                return rt.reply(req.clone(), resp).await.map(Some);
+            }
+
+            Request::Read {
+                after: after @ Some(_),
+                limit,
+                ..
+            }
+            | Request::Read {
+                after,
+                limit: limit @ Some(_),
+                ..
+            } => {
+                Metrics::incr(&node.metrics.reads_served);
+                let limit = limit.unwrap_or(MAX_READ_PAGE).min(MAX_READ_PAGE);
+                let (values, next) = node
+                    .db()?
+                    .broadcast_page(after, limit)
+                    .await
+                    .map_err(db_error)?;
+
+                let resp = Response::ok("read_ok")
+                    .with("messages", values)
+                    .with("next_cursor", next)
+                    .with("node", node.node_id(rt));
+                return rt.reply(req.clone(), resp).await.map(Some);
+            }
+
+            Request::Read { min, max, .. } => {
+                Metrics::incr(&node.metrics.reads_served);
+                // values go straight into the json array, no Vec<u64> copy of a large set first
+                let values: Vec<Value> = if min.is_none() && max.is_none() {
+                    let mut values: Vec<Value> = node
+                        .seen
+                        .read()
+                        .unwrap()
+                        .iter()
+                        .map(|v| Value::from(*v))
+                        .collect();
+                    // blobs follow the integers, bounded reads only cover integers
+                    values.extend(node.blobs.read().unwrap().values().cloned());
+                    values
+                } else {
+                    // bounded reads let peers fetch just the slice they are missing
+                    node.db()?
+                        .fold_values(
+                            min.unwrap_or(u64::MIN),
+                            max.unwrap_or(u64::MAX),
+                            vec![],
+                            |mut values, v| {
+                                values.push(Value::from(v));
+                                values
+                            },
+                        )
+                        .await
+                        .map_err(db_error)?
+                };
+
+                // node is for scripts collecting reads, maelstrom's checker only looks at messages
+                let resp = Response::ok("read_ok")
+                    .with("messages", Value::Array(values))
+                    .with("node", node.node_id(rt));
+                return rt.reply(req.clone(), resp).await.map(Some);
+            }
+
+            Request::ReadOk { messages } => {
+                node.merge_broadcast_values(messages).await?;
+                return Ok(Some(()));
+            }
+
+            // a step of a peer's `sync_merkle`: which of its nodes differ from ours, and on the
+            // leaf level our values it is missing before merging its own
+            Request::SyncMerkle {
+                level,
+                nodes,
+                messages,
+            } => {
+                if level > merkle::DEPTH {
+                    return Err(Error::MalformedRequest.into());
+                }
+                let differing: Vec<u32> = {
+                    let merkle = node.merkle.lock().unwrap();
+                    nodes
+                        .into_iter()
+                        .filter(|(index, hash)| merkle.hash(level, *index) != Some(*hash))
+                        .map(|(index, _)| index)
+                        .collect()
+                };
+                let missing: Vec<u64> = if level == merkle::DEPTH {
+                    let theirs: HashSet<u64> = messages.iter().copied().collect();
+                    let ours = values_in_leaves(&node.seen.read().unwrap(), &differing);
+                    ours.into_iter()
+                        .filter(|value| !theirs.contains(value))
+                        .collect()
+                } else {
+                    vec![]
+                };
+                node.merge_broadcast_values(messages).await?;
+
+                let resp = Response::ok("sync_merkle_ok")
+                    .with("differing", differing)
+                    .with("messages", missing);
+                return rt.reply(req.clone(), resp).await.map(Some);
+            }
+
+            // anti-entropy from a peer, reply with what it is missing before merging its values
+            Request::SyncValues { messages } => {
+                let missing: Vec<u64> = {
+                    let theirs: BTreeSet<u64> = messages.iter().copied().collect();
+                    let seen = node.seen.read().unwrap();
+                    seen.difference(&theirs).copied().collect()
+                };
+                node.merge_broadcast_values(messages).await?;
+
+                let resp = Response::ok("sync_values_ok").with("messages", missing);
+                return rt.reply(req.clone(), resp).await.map(Some);
+            }
+
+            _ => return Ok(None),
+        };
+    }
+}
+
+// challenge #4 - grow-only counter, and its pn-counter variant
+struct CounterWorkload {
+    // accept negative deltas and read increments minus decrements
+    pn: bool,
+}
+
+#[async_trait]
+impl Workload for CounterWorkload {
+    async fn handle(
+        &self,
+        node: &Handler,
+        rt: &Runtime,
+        req: &Message,
+        request: Request,
+    ) -> Result<Option<()>> {
+        match request {
+            // negative deltas only make sense for pn-counter
+            Request::Add { delta, .. } => {
+                let delta = delta.ok_or(Error::MalformedRequest)?;
+                if delta < 0 && !self.pn {
+                    return Err(Error::MalformedRequest.into());
+                }
+                node.db()?
+                    .add(node.node_id(rt), delta)
+                    .await
+                    .map_err(db_error)?;
+
+                let resp = Response::ok("add_ok");
+                return node.reply(rt, req, resp).await;
+            }
+
+            Request::Counters {
+                counters,
+                decrements,
+            } => {
+                let theirs = PNCounter {
+                    increments: counters.into(),
+                    decrements: decrements.into(),
+                };
+                node.db()?.merge_counters(theirs).await.map_err(db_error)?;
+                return Ok(Some(()));
+            }
+
+            Request::Read { .. } => {
+                Metrics::incr(&node.metrics.reads_served);
+                let counter = node.db()?.pn_counter().await.map_err(db_error)?;
+                let value: i64 = if self.pn {
+                    counter.value()
+                } else {
+                    counter.increments.value() as i64
+                };
+
+                let resp = Response::ok("read_ok").with("value", value);
+                return rt.reply(req.clone(), resp).await.map(Some);
+            }
+
+            _ => return Ok(None),
+        };
+    }
+}
+
+// grow-only set of string elements
+struct GSetWorkload;
+
+#[async_trait]
+impl Workload for GSetWorkload {
+    async fn handle(
+        &self,
+        node: &Handler,
+        rt: &Runtime,
+        req: &Message,
+        request: Request,
+    ) -> Result<Option<()>> {
+        match request {
+            Request::Add { element, .. } => {
+                let element = element.ok_or(Error::MalformedRequest)?;
+                node.db()?.add_element(&element).await.map_err(db_error)?;
+
+                let resp = Response::ok("add_ok");
+                return node.reply(rt, req, resp).await;
+            }
+
+            Request::Elements { elements } => {
+                node.db()?
+                    .merge_g_set(elements.into_iter().collect())
+                    .await
+                    .map_err(db_error)?;
+                return Ok(Some(()));
+            }
+
+            Request::Read { .. } => {
+                Metrics::incr(&node.metrics.reads_served);
+                let set = node.db()?.g_set().await.map_err(db_error)?;
+
+                let resp = Response::ok("read_ok").with("value", set.value());
+                return rt.reply(req.clone(), resp).await.map(Some);
+            }
+
+            _ => return Ok(None),
+        };
+    }
+}
+
+// challenge #5 - kafka-style log
+struct KafkaWorkload;
+
+#[async_trait]
+impl Workload for KafkaWorkload {
+    async fn handle(
+        &self,
+        node: &Handler,
+        rt: &Runtime,
+        req: &Message,
+        request: Request,
+    ) -> Result<Option<()>> {
+        match request {
+            Request::Send { key, msg } => {
+                let offset = node.db()?.log_send(&key, msg).await.map_err(db_error)?;
+
+                let resp = Response::ok("send_ok").with("offset", offset);
+                return node.reply(rt, req, resp).await;
+            }
+
+            Request::Poll { offsets } => {
+                let db = node.db()?;
+
+                let mut msgs = HashMap::new();
+                for (key, offset) in offsets {
+                    let entries = db
+                        .log_poll(&key, offset, MAX_POLL_ENTRIES)
+                        .await
+                        .map_err(db_error)?;
+                    msgs.insert(key, entries);
+                }
+
+                let resp = Response::ok("poll_ok").with("msgs", serde_json::to_value(msgs)?);
+                return rt.reply(req.clone(), resp).await.map(Some);
+            }
+
+            Request::CommitOffsets { offsets } => {
+                node.db()?
+                    .log_commit_offsets(offsets)
+                    .await
+                    .map_err(|e| match e {
+                        DbError::OffsetNotSent { .. } => {
+                            warn!("Rejected commit_offsets: {}", e);
+                            Error::PreconditionFailed
+                        }
+                        e => db_error(e),
+                    })?;
+
+                let resp = Response::ok("commit_offsets_ok");
+                return node.reply(rt, req, resp).await;
+            }
+
+            Request::ListCommittedOffsets { keys } => {
+                let offsets = node
+                    .db()?
+                    .log_committed_offsets(keys)
+                    .await
+                    .map_err(db_error)?;
+
+                let resp = Response::ok("list_committed_offsets_ok")
+                    .with("offsets", serde_json::to_value(offsets)?);
+                return rt.reply(req.clone(), resp).await.map(Some);
+            }
+
+            _ => return Ok(None),
+        };
+    }
+}
+
+// challenge #6 - totally-available transactions
+struct TxnWorkload;
+
+#[async_trait]
+impl Workload for TxnWorkload {
+    async fn handle(
+        &self,
+        node: &Handler,
+        rt: &Runtime,
+        req: &Message,
+        request: Request,
+    ) -> Result<Option<()>> {
+        match request {
+            Request::Txn { txn } => {
+                let ops = txn
+                    .iter()
+                    .map(parse_txn_op)
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(Error::MalformedRequest)?;
+
+                let results = node.db()?.apply_txn(ops).await.map_err(db_error)?;
+                let txn: Vec<[Value; 3]> = results.into_iter().map(txn_op_to_json).collect();
+
+                let resp = Response::ok("txn_ok").with("txn", txn);
+                return node.reply(rt, req, resp).await;
+            }
+
+            _ => return Ok(None),
+        };
+    }
+}
+
+// lin-kv - linearizable key/value store, every op goes straight to the local db
+struct LinKvWorkload;
+
+#[async_trait]
+impl Workload for LinKvWorkload {
+    async fn handle(
+        &self,
+        node: &Handler,
+        rt: &Runtime,
+        req: &Message,
+        request: Request,
+    ) -> Result<Option<()>> {
+        match request {
+            Request::Read { key: Some(key), .. } => {
+                let value = node
+                    .db()?
+                    .kv_read(key)
+                    .await
+                    .map_err(db_error)?
+                    .ok_or(Error::KeyDoesNotExist)?;
+
+                let resp = Response::ok("read_ok").with("value", value);
+                return rt.reply(req.clone(), resp).await.map(Some);
+            }
+
+            Request::Write { key, value } => {
+                node.db()?.kv_write(key, value).await.map_err(db_error)?;
+
+                let resp = Response::ok("write_ok");
+                return node.reply(rt, req, resp).await;
+            }
+
+            Request::Cas { key, from, to } => {
+                node.db()?
+                    .kv_cas(key, from, to)
+                    .await
+                    .map_err(|e| match e {
+                        DbError::KeyNotFound(_) => Error::KeyDoesNotExist,
+                        DbError::CasMismatch { .. } => Error::PreconditionFailed,
+                        e => db_error(e),
+                    })?;
+
+                let resp = Response::ok("cas_ok");
+                return node.reply(rt, req, resp).await;
+            }
+
+            _ => return Ok(None),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a handler whose db is open, as if init had run. Only the persistence feature has an
+    // in-memory db.
+    #[cfg(feature = "persistence")]
+    fn initialized(config: Config) -> Handler {
+        let handler = Handler::new(config);
+        assert!(handler.db.set(Db::new_in_memory().unwrap()).is_ok());
+        handler
+    }
+
+    #[cfg(feature = "persistence")]
+    #[tokio::test]
+    async fn node_counter_ids_are_unique() {
+        let handler = initialized(Config::default());
+        let mut ids = HashSet::new();
+        for _ in 0..100_000 {
+            assert!(ids.insert(handler.next_counter_id().await.unwrap()));
+        }
+    }
+
+    #[test]
+    fn pending_over_the_cap_drops_the_oldest() {
+        let start = Instant::now();
+        let mut pending = HashMap::new();
+        for message in 0..100u64 {
+            let mut retry = PendingRetry::new(Duration::from_millis(100), 0);
+            retry.queued_at = start + Duration::from_millis(message);
+            pending.insert(message, retry);
+            drop_oldest(&mut pending, 10);
+            assert!(pending.len() <= 10);
+        }
+        let mut left: Vec<u64> = pending.into_keys().collect();
+        left.sort();
+        assert_eq!(left, (90..100).collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "persistence")]
+    #[tokio::test]
+    async fn read_ok_merge_inserts_only_new_values() {
+        let handler = initialized(Config::default());
+        let known: Vec<u64> = (0..990).collect();
+        assert_eq!(
+            handler.merge_broadcast_values(known).await.unwrap().len(),
+            990
+        );
+
+        let inserted = handler
+            .merge_broadcast_values((0..1000).collect())
+            .await
+            .unwrap();
+        assert_eq!(inserted, (990..1000).collect::<Vec<_>>());
+        assert_eq!(handler.db().unwrap().count().await.unwrap(), 1000);
+    }
+
+    // node -> peers, as the addressbook hands the graph out
+    fn graph(edges: &[(&str, &[&str])]) -> HashMap<String, HashSet<String>> {
+        edges
+            .iter()
+            .map(|(node, peers)| {
+                let peers = peers.iter().map(|peer| peer.to_string()).collect();
+                (node.to_string(), peers)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn second_component_is_unreachable() {
+        let connected = graph(&[("n1", &["n2"]), ("n2", &["n3"])]);
+        assert!(unreachable_nodes(&connected).is_empty());
+
+        let split = graph(&[("n1", &["n2"]), ("n3", &["n4"])]);
+        let unreachable: Vec<String> = unreachable_nodes(&split).into_iter().collect();
+        assert_eq!(unreachable, vec!["n3", "n4"]);
+    }
+
+    // more ids than fit in a millisecond's sequence, so the rollover spin is taken too
+    #[test]
+    fn snowflakes_increase_within_a_node() {
+        let handler = Handler::new(Config::default());
+        let mut last = 0;
+        for _ in 0..20_000 {
+            let id = handler.next_snowflake("n1");
+            assert!(id > last, "{} after {}", id, last);
+            last = id;
+        }
+    }
+
+    #[test]
+    fn resent_request_gets_the_first_reply() {
+        let mut replies = ReplyCache::default();
+        let key = ("c1".to_string(), 7);
+        assert!(replies.begin(key.clone()).is_none());
+        // still being handled
+        assert_eq!(replies.begin(key.clone()), Some(None));
+
+        replies.finish(&key, MessageBody::new().with_type("add_ok"));
+        let reply = replies.begin(key.clone()).unwrap().unwrap();
+        assert_eq!(reply.typ, "add_ok");
+        // another client's request with the same msg_id is a different one
+        assert!(replies.begin(("c2".to_string(), 7)).is_none());
+    }
+
+    #[test]
+    fn abandoned_request_is_processed_again() {
+        let mut replies = ReplyCache::default();
+        let key = ("c1".to_string(), 7);
+        replies.begin(key.clone());
+        replies.abandon(&key);
+        assert!(replies.begin(key).is_none());
+    }
+
+    fn edges(edges: &[(&str, &str)]) -> BTreeSet<Edge> {
+        edges.iter().map(|(a, b)| edge(a, b)).collect()
+    }
+
+    const RING: &[(&str, &str)] = &[
+        ("n1", "n2"),
+        ("n2", "n3"),
+        ("n3", "n4"),
+        ("n4", "n5"),
+        ("n5", "n1"),
+    ];
+
+    #[test]
+    fn nearly_identical_topology_keeps_the_tree() {
+        let mut tree = SpanningTree::default();
+        tree.update(edges(RING));
+        let before = tree.tree.clone();
+
+        // a chord the breadth-first walk doesn't need
+        let mut chord = edges(RING);
+        chord.insert(edge("n3", "n5"));
+        tree.update(chord.clone());
+        assert_eq!(tree.tree, before);
+        assert_eq!(tree.edges, chord);
+    }
+
+    #[test]
+    fn tree_only_depends_on_the_current_topology() {
+        let mut without_n1_n2 = edges(RING);
+        without_n1_n2.remove(&edge("n1", "n2"));
+
+        let mut direct = SpanningTree::default();
+        direct.update(edges(RING));
+        let mut detour = SpanningTree::default();
+        detour.update(without_n1_n2);
+        detour.update(edges(RING));
+        assert_eq!(detour.tree, direct.tree);
+        assert_eq!(detour.neighbours("n1"), direct.neighbours("n1"));
+    }
+
+    #[test]
+    fn known_peers_are_sorted_without_the_node_itself() {
+        let handler = Handler::new(Config::default());
+        for peer in ["n3", "n1", "n2"] {
+            handler.addressbook.add_peer("n1", peer);
+        }
+        assert_eq!(handler.known_peers(), vec!["n2", "n3"]);
+    }
+
+    #[test]
+    fn compaction_waits_for_writes_to_stop() {
+        let idle = Duration::from_secs(10);
+        let start = std::time::Instant::now();
+        // written to every second, never idle for long enough
+        for second in 1..30 {
+            let now = start + Duration::from_secs(second);
+            assert!(!compaction_due(
+                now - Duration::from_secs(1),
+                None,
+                idle,
+                now
+            ));
+        }
+
+        let last_write = start;
+        let now = start + idle;
+        assert!(compaction_due(last_write, None, idle, now));
+        // once per idle stretch
+        assert!(!compaction_due(last_write, Some(now), idle, now + idle));
+        let next_write = now + Duration::from_secs(1);
+        assert!(compaction_due(
+            next_write,
+            Some(now),
+            idle,
+            next_write + idle
+        ));
+    }
+
+    #[test]
+    fn batched_ack_clears_exactly_the_acked_values() {
+        let base = Duration::from_millis(100);
+        let retries = |values: &[u64]| {
+            let retries: HashMap<u64, PendingRetry> = values
+                .iter()
+                .map(|value| (*value, PendingRetry::new(base, 0)))
+                .collect();
+            retries
+        };
+        let pending = Mutex::new(HashMap::from([
+            ("n2".to_string(), retries(&[1, 2, 3])),
+            ("n3".to_string(), retries(&[1, 3])),
+        ]));
+
+        clear_acked(&pending, "n2", &[1, 3], base);
+        let pending = pending.into_inner().unwrap();
+        assert_eq!(sorted_keys(&pending["n2"]), vec![2]);
+        assert_eq!(sorted_keys(&pending["n3"]), vec![1, 3]);
+    }
+
+    // without storage a retry can't succeed, the client has to be told it's not supported
+    #[cfg(not(feature = "persistence"))]
+    #[test]
+    fn disabled_db_is_not_supported() {
+        assert_eq!(db_error(DbError::Disabled).code(), 10);
+    }
+
+    // a read of a key a write could never have stored gives the same reason as the write
+    #[cfg(not(feature = "persistence"))]
+    #[tokio::test]
+    async fn disabled_kv_read_is_not_supported() {
+        let db = Db::open_with_cache("n1.redb", false, None).unwrap();
+        let read = db.kv_read(1).await.unwrap_err();
+        let write = db.kv_write(1, 1).await.unwrap_err();
+        assert_eq!(db_error(read).code(), 10);
+        assert_eq!(db_error(write).code(), 10);
+    }
+
+    #[cfg(feature = "persistence")]
+    #[test]
+    fn failing_db_is_temporarily_unavailable() {
+        let e = std::io::Error::from(std::io::ErrorKind::Interrupted);
+        assert_eq!(db_error(DbError::Io(e)).code(), 11);
+    }
+
+    #[test]
+    fn db_timeout_is_indefinite() {
+        let e = db_error(DbError::Timeout(Duration::from_secs(1)));
+        assert!(matches!(e, Error::Timeout));
+    }
+
+    #[test]
+    fn counter_overflow_is_aborted() {
+        let e = db_error(DbError::CounterOverflow {
+            node_id: "n1".to_string(),
+        });
+        assert!(matches!(e, Error::Abort));
+    }
+
+    // the value is in the db but not in seen, as after a write that timed out and then
+    // committed. The client's retry must count as new so the value gets forwarded.
+    #[cfg(feature = "persistence")]
+    #[tokio::test]
+    async fn retry_after_a_late_commit_is_new() {
+        let handler = initialized(Config::default());
+        let db = handler.db().unwrap();
+        db.set_broadcast_id(5).await.unwrap();
+        assert!(handler.store_broadcast(db, 5).await.unwrap());
+        assert!(!handler.store_broadcast(db, 5).await.unwrap());
+
+        db.set_broadcast_ids(&[6, 7]).await.unwrap();
+        assert_eq!(
+            handler.merge_broadcast_values(vec![6, 7, 8]).await.unwrap(),
+            vec![6, 7, 8]
+        );
+    }
+
+    #[cfg(feature = "persistence")]
+    fn broadcast(message: u64) -> Message {
+        serde_json::from_value(json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": {"type": "broadcast", "message": message, "msg_id": message},
+        }))
+        .unwrap()
+    }
+
+    // an open db isn't enough, its values may still be loading
+    #[cfg(feature = "persistence")]
+    #[tokio::test]
+    async fn messages_are_held_until_the_db_is_loaded() {
+        let handler = initialized(Config::default());
+        assert_eq!(handler.hold_until_init(&broadcast(1)), Some(true));
+
+        handler.init_db("n1").await.unwrap();
+        assert_eq!(handler.hold_until_init(&broadcast(2)), None);
+        assert_eq!(handler.pre_init.lock().unwrap().held.len(), 1);
+    }
+
+    #[cfg(feature = "persistence")]
+    #[tokio::test]
+    async fn messages_are_turned_away_after_a_failed_init() {
+        let dir = std::env::temp_dir().join(format!("gossip-handler-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("n1.redb"), b"not a redb file").unwrap();
+        let handler = Handler::new(Config {
+            db_dir: Some(dir.clone()),
+            recover_db: false,
+            ..Config::default()
+        });
+        assert_eq!(handler.hold_until_init(&broadcast(1)), Some(true));
+
+        assert!(handler.init_db("n1").await.is_err());
+        assert_eq!(handler.hold_until_init(&broadcast(2)), Some(false));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
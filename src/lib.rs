@@ -1 +1,18 @@
+pub mod addressbook;
+pub mod config;
+pub mod crdt;
+#[cfg(feature = "persistence")]
 pub mod db;
+#[cfg(not(feature = "persistence"))]
+#[path = "nodb.rs"]
+pub mod db;
+pub mod handler;
+#[cfg(feature = "persistence")]
+pub mod log;
+pub mod merkle;
+pub mod protocol;
+pub mod stats;
+
+pub use config::Config;
+pub use handler::{Handler, HandlerSnapshot};
+pub use protocol::{Request, Response, Topology};
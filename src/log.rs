@@ -0,0 +1,211 @@
+use crate::db::{dump_table, Db, DbError};
+use redb::{ReadTransaction, ReadableTable, TableDefinition, TableError, TableHandle};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+// committed offset per log key, each log itself lives in its own table (see `log_table`)
+const COMMITS: TableDefinition<&str, u64> = TableDefinition::new("log_commits");
+// prefix of the per-key log tables. No other table name contains a '/', so no key, not even
+// "commits", can name a log table that clashes with one of them.
+const LOG_PREFIX: &str = "log/";
+
+fn log_table(key: &str) -> String {
+    format!("{}{}", LOG_PREFIX, key)
+}
+
+// every log as key -> [[offset, msg], ..]
+pub(crate) fn export_logs(read_txn: &ReadTransaction) -> Result<Value, DbError> {
+    let mut logs = Map::new();
+    for handle in read_txn.list_tables()? {
+        let name = handle.name();
+        let Some(key) = name.strip_prefix(LOG_PREFIX) else {
+            continue;
+        };
+        let entries = dump_table(
+            read_txn,
+            TableDefinition::<u64, u64>::new(name),
+            |offset, msg| Ok(json!([offset, msg])),
+        )?;
+        logs.insert(key.to_string(), entries.into());
+    }
+    Ok(logs.into())
+}
+
+pub(crate) fn export_committed_offsets(read_txn: &ReadTransaction) -> Result<Value, DbError> {
+    let committed = dump_table(read_txn, COMMITS, |key, offset| {
+        Ok((key.to_string(), offset.into()))
+    })?;
+    Ok(Value::Object(committed.into_iter().collect()))
+}
+
+impl Db {
+    // appends msg to the log for key and returns the offset it was stored at
+    pub async fn log_send(&self, key: &str, msg: u64) -> Result<u64, DbError> {
+        let name = log_table(key);
+
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
+            let offset = {
+                let mut table = write_txn.open_table(TableDefinition::<u64, u64>::new(&name))?;
+                let offset = table
+                    .last()?
+                    .map(|(offset, _)| offset.value() + 1)
+                    .unwrap_or_default();
+                table.insert(offset, msg)?;
+                offset
+            };
+            write_txn.commit()?;
+
+            Ok(offset)
+        })
+        .await
+    }
+
+    // returns at most limit (offset, msg) pairs stored at or after offset, empty for keys that
+    // were never written and offsets past the end of the log
+    pub async fn log_poll(
+        &self,
+        key: &str,
+        offset: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, u64)>, DbError> {
+        let mut entries = vec![];
+
+        let db = self.db.clone();
+        let name = log_table(key);
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            {
+                let table = match read_txn.open_table(TableDefinition::<u64, u64>::new(&name)) {
+                    Ok(table) => table,
+                    Err(TableError::TableDoesNotExist(_)) => return Ok(entries),
+                    Err(e) => return Err(e.into()),
+                };
+
+                let iter = table.range(offset..)?;
+                for res in iter.take(limit) {
+                    let (offset, msg) = res?;
+                    entries.push((offset.value(), msg.value()));
+                }
+            }
+
+            Ok(entries)
+        })
+        .await
+    }
+
+    // all or nothing: if any offset is past what was sent for its key nothing is committed
+    pub async fn log_commit_offsets(&self, offsets: HashMap<String, u64>) -> Result<(), DbError> {
+        self.write(move |db| {
+            let write_txn = db.begin_write()?;
+            {
+                for (key, offset) in &offsets {
+                    let last = write_txn
+                        .open_table(TableDefinition::<u64, u64>::new(&log_table(key)))?
+                        .last()?
+                        .map(|(offset, _)| offset.value());
+                    if last.is_none_or(|last| *offset > last) {
+                        return Err(DbError::OffsetNotSent {
+                            key: key.clone(),
+                            offset: *offset,
+                        });
+                    }
+                }
+
+                let mut table = write_txn.open_table(COMMITS)?;
+                for (key, offset) in offsets {
+                    table.insert(key.as_str(), offset)?;
+                }
+            }
+            write_txn.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    // keys that were never committed are left out of the result
+    pub async fn log_committed_offsets(
+        &self,
+        keys: Vec<String>,
+    ) -> Result<HashMap<String, u64>, DbError> {
+        let mut offsets = HashMap::new();
+
+        let db = self.db.clone();
+        self.blocking(move || {
+            let db = db.read().unwrap();
+            let read_txn = db.begin_read()?;
+            {
+                let table = match read_txn.open_table(COMMITS) {
+                    Ok(table) => table,
+                    Err(TableError::TableDoesNotExist(_)) => return Ok(offsets),
+                    Err(e) => return Err(e.into()),
+                };
+
+                for key in keys {
+                    if let Some(offset) = table.get(key.as_str())? {
+                        offsets.insert(key, offset.value());
+                    }
+                }
+            }
+
+            Ok(offsets)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the key's log table must not be mistaken for the table of committed offsets
+    #[tokio::test]
+    async fn key_named_commits_has_its_own_log() {
+        let db = Db::new_in_memory().unwrap();
+        assert_eq!(db.log_send("commits", 10).await.unwrap(), 0);
+        assert_eq!(db.log_send("commits", 11).await.unwrap(), 1);
+        db.log_commit_offsets(HashMap::from([("commits".to_string(), 1)]))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            db.log_poll("commits", 0, 10).await.unwrap(),
+            vec![(0, 10), (1, 11)]
+        );
+        let committed = db
+            .log_committed_offsets(vec!["commits".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(committed, HashMap::from([("commits".to_string(), 1)]));
+    }
+
+    #[tokio::test]
+    async fn polling_unwritten_keys_and_past_the_end_is_empty() {
+        let db = Db::new_in_memory().unwrap();
+        assert!(db.log_poll("k1", 0, 10).await.unwrap().is_empty());
+        db.log_send("k1", 10).await.unwrap();
+        assert!(db.log_poll("k1", 1, 10).await.unwrap().is_empty());
+        assert!(db.log_poll("k2", 0, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn committing_past_what_was_sent_is_rejected() {
+        let db = Db::new_in_memory().unwrap();
+        db.log_send("k1", 10).await.unwrap();
+        let offsets = HashMap::from([("k1".to_string(), 0), ("k2".to_string(), 0)]);
+        assert!(matches!(
+            db.log_commit_offsets(offsets).await,
+            Err(DbError::OffsetNotSent { offset: 0, .. })
+        ));
+        let offsets = HashMap::from([("k1".to_string(), 1)]);
+        assert!(matches!(
+            db.log_commit_offsets(offsets).await,
+            Err(DbError::OffsetNotSent { offset: 1, .. })
+        ));
+        // all or nothing, k1's valid offset wasn't committed along with k2's
+        let committed = db.log_committed_offsets(vec!["k1".to_string()]).await;
+        assert!(committed.unwrap().is_empty());
+    }
+}
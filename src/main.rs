@@ -1,180 +1,114 @@
-use async_trait::async_trait;
-use flyio_gossip_glomers_challenge::db::Db;
-use log::info;
-use maelstrom::protocol::Message;
-use maelstrom::{done, Node, Result, Runtime};
-use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex};
-use tokio::sync::OnceCell;
-use uuid::Uuid;
+use flyio_gossip_glomers_challenge::{Config, Handler};
+use maelstrom::protocol::{Message, MessageBody};
+use maelstrom::{Result, Runtime};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
 
 fn main() -> Result<()> {
     Runtime::init(try_main())
 }
 
 async fn try_main() -> Result<()> {
-    let handler = Arc::new(Handler::default());
-    Runtime::new().with_handler(handler).run().await
-}
-
-#[derive(Default)]
-struct Handler {
-    db: OnceCell<Db>,
-    addressbook: Arc<Mutex<HashMap<String, HashSet<String>>>>,
-}
+    init_tracing();
+    let mut config = Config::from_env();
+    if let Some(node_id) = node_id_arg() {
+        config.node_id = Some(node_id);
+    }
+    let handler = Arc::new(Handler::new(config));
+    let runtime = Runtime::new().with_handler(handler.clone());
+    handler.init_standalone(&runtime).await?;
+
+    let mut tasks = handler.spawn_tasks(&runtime);
+
+    let (input, stdin_writer) = tokio::io::duplex(64 * 1024);
+    {
+        let runtime = runtime.clone();
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = filter_stdin(runtime, stdin_writer).await {
+                warn!("Reading stdin failed: {}", e);
+            }
+        }));
+    }
 
-impl Handler {
-    async fn init_db(&self, node_id: &str) -> Result<()> {
-        self.db
-            .get_or_try_init(|| async { Db::new(node_id) })
-            .await?;
-        Ok(())
+    let result = tokio::select! {
+        result = runtime.run_with(BufReader::new(input)) => result,
+        _ = shutdown_signal() => {
+            info!("Shutdown signal received");
+            Ok(())
+        }
+    };
+    handler.shutdown(&runtime).await;
+    for task in tasks {
+        task.abort();
     }
+    result
 }
 
-fn add_known_peer(addressbook: Arc<Mutex<HashMap<String, HashSet<String>>>>, peer: &str) {
-    let mut addressbook = addressbook.lock().unwrap();
-    if addressbook.get(peer).is_none() {
-        addressbook.insert(peer.to_string(), HashSet::new());
+// `--node-id <id>` or `--node-id=<id>`, takes precedence over GOSSIP_NODE_ID
+fn node_id_arg() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--node-id" {
+            return args.next();
+        }
+        if let Some(node_id) = arg.strip_prefix("--node-id=") {
+            return Some(node_id.to_string());
+        }
     }
+    None
 }
 
-#[async_trait]
-impl Node for Handler {
-    async fn process(&self, rt: Runtime, req: Message) -> Result<()> {
-        let msg: Result<Request> = req.body.as_obj();
-
-        match msg {
-            Ok(Request::Init { node_ids }) => {
-                // init has been proceeded by the runtime
-                // we now know the node_id
-                if rt.node_id().is_empty() {
-                    return Err("node id is empty".into());
-                }
-
-                for node_id in node_ids {
-                    add_known_peer(self.addressbook.clone(), &node_id);
-                }
-
-                self.init_db(rt.node_id()).await?;
-            }
-            // challenge #1
-            Ok(Request::Echo { .. }) => {
-                let resp = req.body.clone().with_type("echo_ok");
-                return rt.reply(req, resp).await;
-            }
-
-            // challenge #2 - unique id
-            Ok(Request::Generate {}) => {
-                let id = Uuid::new_v4();
-                let mut resp = req.body.clone().with_type("generate_ok");
-                resp.extra.insert("id".to_string(), id.to_string().into());
-                return rt.reply(req, resp).await;
-            }
-
-            // challenge #3 - broadcast & topology
-            Ok(Request::Broadcast { message }) => {
-                self.db
-                    .get()
-                    .ok_or("node is not initialized".to_string())?
-                    .set_broadcast_id(message)
-                    .await?;
-
-                let neighbours: Vec<String> = {
-                    let addressbook = self.addressbook.lock().unwrap();
-                    addressbook.keys().cloned().collect()
-                };
-
-                for node in neighbours {
-                    if node == rt.node_id() {
-                        continue;
-                    }
-
-                    let rt_clone = rt.clone();
-                    tokio::spawn(async move {
-                        let mut reties = 0;
-
-                        while rt_clone
-                            .rpc(node.to_string(), Request::Broadcast { message })
-                            .await
-                            .is_err()
-                        {
-                            reties += 1;
-                            if reties > 3 {
-                                break;
-                            }
-
-                            // sleep with exponential backoff + random component
-                            tokio::time::sleep(std::time::Duration::from_secs(
-                                2_u64.pow(reties) + rand::random::<u64>() % 1000,
-                            ))
-                            .await;
-                        }
-                    });
-                }
-
-                let mut resp = req.body.clone().with_type("broadcast_ok");
-                resp.extra.clear();
-                return rt.reply(req, resp).await;
-            }
-
-            Ok(Request::BroadcastOk {}) => info!("Broadcast Ok"),
-
-            Ok(Request::Read {}) => {
-                let values = self
-                    .db
-                    .get()
-                    .ok_or("node is not initialized".to_string())?
-                    .seen_broadcast_values()
-                    .await?;
-
-                let mut resp = req.body.clone().with_type("read_ok");
-                resp.extra.insert("messages".to_string(), values.into());
-                return rt.reply(req, resp).await;
-            }
+// the node logs through tracing, the runtime's own logs still go through its env_logger. Both
+// write plain lines to stderr, which maelstrom keeps per node, and RUST_LOG filters both.
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .init();
+}
 
-            Ok(Request::ReadOk { messages }) => {
-                for message in messages {
-                    self.db
-                        .get()
-                        .ok_or("node is not initialized".to_string())?
-                        .set_broadcast_id(message)
+// the runtime treats a second init as fatal, so repeats are acked here and never reach it.
+// Only lines that could be an init are parsed, everything else is passed through as it is.
+async fn filter_stdin(rt: Runtime, mut out: DuplexStream) -> Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut inited = false;
+    while let Some(line) = lines.next_line().await? {
+        if !line.contains("\"init\"") {
+            out.write_all(line.as_bytes()).await?;
+            out.write_all(b"\n").await?;
+            continue;
+        }
+        if let Ok(msg) = serde_json::from_str::<Message>(&line) {
+            if msg.get_type() == "init" {
+                if inited {
+                    warn!("Ignoring repeated init from {}", msg.src);
+                    rt.reply(msg, MessageBody::new().with_type("init_ok"))
                         .await?;
+                    continue;
                 }
+                inited = true;
             }
-
-            Ok(Request::Topology { topology }) => {
-                {
-                    let mut addressbook = self.addressbook.lock().unwrap();
-                    for (node, peers) in topology {
-                        addressbook.entry(node).or_default().extend(peers);
-                    }
-                }
-
-                let mut resp = req.body.clone().with_type("topology_ok");
-                resp.extra.clear();
-                return rt.reply(req, resp).await;
-            }
-
-            _ => info!("Message: {:?} failed to match", msg),
-        };
-
-        done(rt, req)
+        }
+        out.write_all(line.as_bytes()).await?;
+        out.write_all(b"\n").await?;
     }
+    Ok(())
 }
 
-type Topology = HashMap<String, Vec<String>>;
-
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "snake_case", tag = "type")]
-enum Request {
-    Init { node_ids: Vec<String> },
-    Read {},
-    ReadOk { messages: Vec<u64> },
-    Generate {},
-    Echo { echo: String },
-    Broadcast { message: u64 },
-    BroadcastOk {},
-    Topology { topology: Topology },
+// SIGTERM is how maelstrom stops nodes at the end of a run, SIGINT is for running by hand
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut term) = signal(SignalKind::terminate()) else {
+        warn!("Failed to install the SIGTERM handler");
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = term.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
 }
@@ -0,0 +1,80 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::Range;
+
+// children per node, as bits of a leaf index
+const BRANCH_BITS: u32 = 4;
+// levels below the root, so 16^2 = 256 leaves
+pub const DEPTH: u8 = 2;
+
+// fixed-depth merkle tree over a set of u64s, for two nodes to find the values their sets
+// differ in without sending either set. A value goes to the leaf picked by its hash, not by
+// the value itself, so the small sequential ints maelstrom broadcasts spread over every leaf
+// instead of piling into the first. A leaf's hash is the wrapping sum of its values' hashes,
+// which doesn't depend on insertion order and is updated in place, and every node above hashes
+// its children. Values can't be removed, the sets only grow.
+#[derive(Clone, Debug)]
+pub struct MerkleSummary {
+    // levels[0] is the root, levels[DEPTH] the leaves
+    levels: Vec<Vec<u64>>,
+}
+
+impl Default for MerkleSummary {
+    fn default() -> Self {
+        let mut summary = Self {
+            levels: (0..=DEPTH)
+                .map(|level| vec![0; 1 << (BRANCH_BITS * u32::from(level))])
+                .collect(),
+        };
+        for level in (0..DEPTH).rev() {
+            for index in 0..summary.levels[usize::from(level)].len() as u32 {
+                summary.rehash(level, index);
+            }
+        }
+        summary
+    }
+}
+
+impl MerkleSummary {
+    // the caller makes sure value wasn't inserted before, a second insert changes the hashes
+    pub fn insert(&mut self, value: u64) {
+        let mut index = Self::leaf(value);
+        let leaf = &mut self.levels[usize::from(DEPTH)][index as usize];
+        *leaf = leaf.wrapping_add(value_hash(value));
+        for level in (0..DEPTH).rev() {
+            index >>= BRANCH_BITS;
+            self.rehash(level, index);
+        }
+    }
+
+    // None for an index the level doesn't have
+    pub fn hash(&self, level: u8, index: u32) -> Option<u64> {
+        self.levels
+            .get(usize::from(level))?
+            .get(index as usize)
+            .copied()
+    }
+
+    pub fn leaf(value: u64) -> u32 {
+        (value_hash(value) % (1 << (BRANCH_BITS * u32::from(DEPTH)))) as u32
+    }
+
+    // indices of a node's children on the next level down
+    pub fn children(index: u32) -> Range<u32> {
+        index << BRANCH_BITS..(index + 1) << BRANCH_BITS
+    }
+
+    fn rehash(&mut self, level: u8, index: u32) {
+        let children = Self::children(index);
+        let mut hasher = DefaultHasher::new();
+        self.levels[usize::from(level) + 1][children.start as usize..children.end as usize]
+            .hash(&mut hasher);
+        self.levels[usize::from(level)][index as usize] = hasher.finish();
+    }
+}
+
+// DefaultHasher::new() is keyed the same in every process, so nodes agree on it
+fn value_hash(value: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,264 @@
+// stand-in for the redb-backed db in builds without the `persistence` feature. It opens
+// like the real one and reads as empty, so a node starts up, answers echo and hands out
+// uuid and snowflake ids. Everything that has to store something fails with `Disabled`,
+// which the workloads report to the client as not-supported. So do lin-kv reads, which
+// would otherwise say key-does-not-exist to a client whose writes were turned away.
+use crate::crdt::{Crdt, GSet, PNCounter};
+pub use crate::stats::{DbStats, OpStats};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// the commit modes the real db takes, kept so the config reads the same either way
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    None,
+    Eventual,
+    Immediate,
+}
+
+pub enum TxnOp {
+    // key and the value read, filled in by `Db::apply_txn`
+    Read(u64, Option<u64>),
+    Write(u64, u64),
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    // the operation needs storage and this build has none
+    Disabled,
+    // never returned here, the workloads match on them
+    Timeout(Duration),
+    OffsetNotSent { key: String, offset: u64 },
+    KeyNotFound(u64),
+    CasMismatch { key: u64, from: u64, current: u64 },
+    // an add that would take a node's sum past u64::MAX
+    CounterOverflow { node_id: String },
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Disabled => write!(f, "built without persistence"),
+            DbError::Timeout(timeout) => write!(f, "db operation timed out after {:?}", timeout),
+            DbError::OffsetNotSent { key, offset } => {
+                write!(f, "offset {} was never sent for key {}", offset, key)
+            }
+            DbError::KeyNotFound(key) => write!(f, "key {} does not exist", key),
+            DbError::CasMismatch { key, from, current } => {
+                write!(f, "key {} holds {}, not {}", key, current, from)
+            }
+            DbError::CounterOverflow { node_id } => {
+                write!(f, "counter of {} would overflow", node_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+pub struct Db {
+    opened: Instant,
+}
+
+impl Db {
+    pub fn open_with_cache(
+        _path: impl AsRef<Path>,
+        _recover: bool,
+        _cache_size: Option<usize>,
+    ) -> Result<Self, DbError> {
+        Ok(Self {
+            opened: Instant::now(),
+        })
+    }
+
+    #[must_use]
+    pub fn with_durability(self, _durability: Durability) -> Self {
+        self
+    }
+
+    #[must_use]
+    pub fn with_write_retries(self, _retries: u32) -> Self {
+        self
+    }
+
+    #[must_use]
+    pub fn with_timeout(self, _timeout: Duration) -> Self {
+        self
+    }
+
+    pub fn stats(&self) -> DbStats {
+        DbStats::default()
+    }
+
+    pub async fn flush(&self) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    pub fn last_write(&self) -> Instant {
+        self.opened
+    }
+
+    pub fn recovered(&self) -> bool {
+        false
+    }
+
+    pub fn file_size(&self) -> Result<u64, DbError> {
+        Ok(0)
+    }
+
+    pub async fn compact(&self) -> Result<bool, DbError> {
+        Ok(false)
+    }
+
+    pub async fn clear(&self) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    pub async fn set_broadcast_id(&self, _id: u64) -> Result<bool, DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn set_broadcast_blob(&self, _bytes: Vec<u8>) -> Result<bool, DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn broadcast_blobs(&self) -> Result<Vec<Vec<u8>>, DbError> {
+        Ok(vec![])
+    }
+
+    pub fn queue_broadcast_id(&self, _id: u64) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn set_broadcast_ids(&self, _ids: &[u64]) -> Result<Vec<u64>, DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn count(&self) -> Result<u64, DbError> {
+        Ok(0)
+    }
+
+    pub async fn seen_broadcast_values(&self) -> Result<Vec<u64>, DbError> {
+        Ok(vec![])
+    }
+
+    pub async fn broadcast_page(
+        &self,
+        _after: Option<u64>,
+        _limit: usize,
+    ) -> Result<(Vec<u64>, Option<u64>), DbError> {
+        Ok((vec![], None))
+    }
+
+    pub async fn fold_values<B, F>(&self, _lo: u64, _hi: u64, init: B, _f: F) -> Result<B, DbError>
+    where
+        B: Send + 'static,
+        F: FnMut(B, u64) -> B + Send + 'static,
+    {
+        Ok(init)
+    }
+
+    pub async fn export(&self) -> Result<Value, DbError> {
+        Ok(json!({}))
+    }
+
+    pub async fn g_set(&self) -> Result<GSet, DbError> {
+        Ok(GSet::default())
+    }
+
+    pub async fn merge_g_set(&self, _other: GSet) -> Result<Vec<String>, DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn add_element(&self, _element: &str) -> Result<bool, DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn add_elements(&self, _elements: Vec<String>) -> Result<Vec<String>, DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn set_id_limit(&self, _limit: u64) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn id_limit(&self) -> Result<u64, DbError> {
+        Ok(0)
+    }
+
+    pub async fn kv_read(&self, _key: u64) -> Result<Option<u64>, DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn kv_write(&self, _key: u64, _value: u64) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn kv_cas(&self, _key: u64, _from: u64, _to: u64) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn set_topology(
+        &self,
+        _topology: HashMap<String, Vec<String>>,
+    ) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    pub async fn topology(&self) -> Result<HashMap<String, Vec<String>>, DbError> {
+        Ok(HashMap::new())
+    }
+
+    pub async fn add(&self, _node_id: &str, _delta: i64) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn pn_counter(&self) -> Result<PNCounter, DbError> {
+        Ok(PNCounter::default())
+    }
+
+    // g-counter value, the sum of increments only
+    pub async fn counter_total(&self) -> Result<u64, DbError> {
+        self.pn_counter().await.map(|c| c.increments.value())
+    }
+
+    // pn-counter value, increments minus decrements across all nodes
+    pub async fn pn_total(&self) -> Result<i64, DbError> {
+        self.pn_counter().await.map(|c| c.value())
+    }
+
+    pub async fn merge_counters(&self, _other: PNCounter) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn apply_txn(&self, _ops: Vec<TxnOp>) -> Result<Vec<TxnOp>, DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn log_send(&self, _key: &str, _msg: u64) -> Result<u64, DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn log_poll(
+        &self,
+        _key: &str,
+        _offset: u64,
+        _limit: usize,
+    ) -> Result<Vec<(u64, u64)>, DbError> {
+        Ok(vec![])
+    }
+
+    pub async fn log_commit_offsets(&self, _offsets: HashMap<String, u64>) -> Result<(), DbError> {
+        Err(DbError::Disabled)
+    }
+
+    pub async fn log_committed_offsets(
+        &self,
+        _keys: Vec<String>,
+    ) -> Result<HashMap<String, u64>, DbError> {
+        Ok(HashMap::new())
+    }
+}
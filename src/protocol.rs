@@ -0,0 +1,398 @@
+use maelstrom::protocol::MessageBody;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::HashMap;
+
+pub type Topology = HashMap<String, Vec<String>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Request {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    Read {
+        // optional inclusive bounds, an unbounded read returns every value
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max: Option<u64>,
+        // lin-kv reads a single key instead
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<u64>,
+        // paged read: up to `limit` values greater than `after`, the reply's `next_cursor` is
+        // the `after` of the next page. Takes precedence over min and max.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    ReadOk {
+        messages: Vec<u64>,
+    },
+    Generate {},
+    // debugging aid, replies with everything the node has stored
+    DumpState {},
+    // debugging aid, forgets every broadcast value so a test can start over
+    Reset {},
+    // for scripts checking on a node, answered even before init
+    Health {},
+    Echo {
+        echo: String,
+    },
+    Broadcast {
+        #[serde(flatten)]
+        values: BroadcastValues,
+        // relays left, absent on client broadcasts
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hops: Option<u8>,
+        // nodes that already have the value, they're skipped when forwarding it
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        seen_by: Vec<String>,
+    },
+    BatchBroadcast {
+        messages: Vec<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hops: Option<u8>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        seen_by: Vec<String>,
+        // non-integer values, see `BroadcastValues::Blob`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        blobs: Vec<Value>,
+    },
+    // acks every value of `messages` at once, the ones of a batch_broadcast it answers
+    BatchBroadcastOk {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        messages: Vec<u64>,
+    },
+    Topology {
+        topology: TopologyForm,
+    },
+    // counters send a delta, g-set an element
+    Add {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delta: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        element: Option<String>,
+    },
+    AddOk {},
+    Counters {
+        counters: HashMap<String, u64>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        decrements: HashMap<String, u64>,
+    },
+    Elements {
+        elements: Vec<String>,
+    },
+    SyncValues {
+        messages: Vec<u64>,
+    },
+    // anti-entropy by merkle summary, see `Handler::sync_merkle`. `nodes` are (index, hash)
+    // pairs of the sender's tree at `level`, on the leaf level its values in those leaves come
+    // along in `messages`
+    SyncMerkle {
+        level: u8,
+        nodes: Vec<(u32, u64)>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        messages: Vec<u64>,
+    },
+    // the indices whose hash differs, on the leaf level with the receiver's values in those
+    // leaves the sender didn't have
+    SyncMerkleOk {
+        differing: Vec<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        messages: Vec<u64>,
+    },
+    SyncValuesOk {
+        messages: Vec<u64>,
+    },
+    Send {
+        key: String,
+        msg: u64,
+    },
+    Poll {
+        offsets: HashMap<String, u64>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, u64>,
+    },
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    Txn {
+        txn: Vec<[Value; 3]>,
+    },
+    TxnOk {
+        txn: Vec<[Value; 3]>,
+    },
+    Write {
+        key: u64,
+        value: u64,
+    },
+    Cas {
+        key: u64,
+        from: u64,
+        to: u64,
+    },
+}
+
+impl Request {
+    // every body field the variant reads besides type and msg_id, flattened ones included
+    pub fn fields(&self) -> &'static [&'static str] {
+        match self {
+            Request::Init { .. } => &["node_id", "node_ids"],
+            Request::Read { .. } => &["min", "max", "key", "after", "limit"],
+            Request::ReadOk { .. } | Request::SyncValues { .. } | Request::SyncValuesOk { .. } => {
+                &["messages"]
+            }
+            Request::Generate {}
+            | Request::DumpState {}
+            | Request::Reset {}
+            | Request::Health {}
+            | Request::AddOk {} => &[],
+            Request::Echo { .. } => &["echo"],
+            Request::Broadcast { .. } => &["message", "messages", "hops", "seen_by"],
+            Request::BatchBroadcast { .. } => &["messages", "hops", "seen_by", "blobs"],
+            Request::BatchBroadcastOk { .. } => &["messages"],
+            Request::Topology { .. } => &["topology"],
+            Request::Add { .. } => &["delta", "element"],
+            Request::Counters { .. } => &["counters", "decrements"],
+            Request::Elements { .. } => &["elements"],
+            Request::SyncMerkle { .. } => &["level", "nodes", "messages"],
+            Request::SyncMerkleOk { .. } => &["differing", "messages"],
+            Request::Send { .. } => &["key", "msg"],
+            Request::Poll { .. } | Request::CommitOffsets { .. } => &["offsets"],
+            Request::ListCommittedOffsets { .. } => &["keys"],
+            Request::Txn { .. } | Request::TxnOk { .. } => &["txn"],
+            Request::Write { .. } => &["key", "value"],
+            Request::Cas { .. } => &["key", "from", "to"],
+        }
+    }
+}
+
+// requests to maelstrom's key-value services, which take any JSON as keys and values. A
+// missing key fails with key-does-not-exist and a cas whose `from` doesn't match with
+// precondition-failed.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum KvRequest {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        // writes `to` if the key doesn't exist yet instead of failing
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+}
+
+// a broadcast carries `message`, or `messages` in the variants that batch on the client.
+// Values that aren't u64, e.g. small objects, are stored as blobs of their JSON.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum BroadcastValues {
+    One { message: u64 },
+    Many { messages: Vec<u64> },
+    Blob { message: Value },
+    Mixed { messages: Vec<Value> },
+}
+
+impl BroadcastValues {
+    // the u64 values and the blobs
+    pub fn into_parts(self) -> (Vec<u64>, Vec<Value>) {
+        match self {
+            BroadcastValues::One { message } => (vec![message], vec![]),
+            BroadcastValues::Many { messages } => (messages, vec![]),
+            BroadcastValues::Blob { message } => (vec![], vec![message]),
+            BroadcastValues::Mixed { messages } => {
+                let (numbers, blobs): (Vec<Value>, Vec<Value>) =
+                    messages.into_iter().partition(Value::is_u64);
+                (numbers.iter().filter_map(Value::as_u64).collect(), blobs)
+            }
+        }
+    }
+}
+
+// a topology as maelstrom sends it, or as the adjacency matrix some test generators produce.
+// Row and column i of the matrix are the i-th node of init's node_ids, a non-zero entry is an
+// edge between the two.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum TopologyForm {
+    List(Topology),
+    Matrix(Vec<Vec<u8>>),
+}
+
+impl TopologyForm {
+    // the adjacency list, a node's peers in nodes order. None for a matrix that isn't square
+    // or doesn't have a row per node.
+    pub fn into_list(self, nodes: &[String]) -> Option<Topology> {
+        match self {
+            TopologyForm::List(topology) => Some(topology),
+            TopologyForm::Matrix(matrix) => {
+                if matrix.len() != nodes.len() || matrix.iter().any(|row| row.len() != nodes.len())
+                {
+                    return None;
+                }
+                let topology = nodes
+                    .iter()
+                    .zip(&matrix)
+                    .map(|(node, row)| {
+                        let peers = nodes
+                            .iter()
+                            .zip(row)
+                            .filter(|(_, edge)| **edge != 0)
+                            .map(|(peer, _)| peer.clone())
+                            .collect();
+                        (node.clone(), peers)
+                    })
+                    .collect();
+                Some(topology)
+            }
+        }
+    }
+
+    // false for a matrix with an edge that only goes one way, lists are taken as they are
+    pub fn is_symmetric(&self) -> bool {
+        match self {
+            TopologyForm::List(_) => true,
+            TopologyForm::Matrix(matrix) => matrix.iter().enumerate().all(|(i, row)| {
+                row.iter().enumerate().all(|(j, edge)| {
+                    let back = matrix.get(j).and_then(|row| row.get(i));
+                    back.is_none_or(|back| (*back != 0) == (*edge != 0))
+                })
+            }),
+        }
+    }
+}
+
+// a reply body holding only the fields given to it, `Runtime::reply` adds in_reply_to. Replies
+// built from a clone of the request would carry its fields along unless they're cleared.
+#[derive(Clone, Debug)]
+pub struct Response(MessageBody);
+
+impl Response {
+    pub fn ok(kind: &str) -> Self {
+        Self(MessageBody::new().with_type(kind))
+    }
+
+    #[must_use]
+    pub fn with(mut self, field: &str, value: impl Into<Value>) -> Self {
+        self.0.extra.insert(field.to_string(), value.into());
+        self
+    }
+
+    pub fn into_body(self) -> MessageBody {
+        self.0
+    }
+}
+
+impl Serialize for Response {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // the static lists have to keep up with the variants, every serialized field must be in them
+    #[test]
+    fn fields_cover_what_requests_serialize() {
+        let requests = [
+            json!({"type": "read", "min": 1, "max": 2, "key": 3, "after": 4, "limit": 5}),
+            json!({"type": "broadcast", "message": 1, "hops": 2, "seen_by": ["n1"]}),
+            json!({"type": "broadcast", "messages": [1], "seen_by": ["n1"]}),
+            json!({"type": "batch_broadcast", "messages": [1], "hops": 2, "seen_by": ["n1"], "blobs": [{}]}),
+            json!({"type": "batch_broadcast_ok", "messages": [1]}),
+            json!({"type": "add", "delta": 1, "element": "a"}),
+            json!({"type": "counters", "counters": {"n1": 1}, "decrements": {"n1": 1}}),
+            json!({"type": "sync_merkle", "level": 1, "nodes": [[0, 1]], "messages": [1]}),
+            json!({"type": "cas", "key": 1, "from": 2, "to": 3}),
+        ];
+        for request in requests {
+            let request: Request = serde_json::from_value(request).unwrap();
+            let Value::Object(serialized) = serde_json::to_value(&request).unwrap() else {
+                panic!("{:?} isn't an object", request);
+            };
+            for field in serialized.keys().filter(|field| *field != "type") {
+                assert!(
+                    request.fields().contains(&field.as_str()),
+                    "{} of {:?}",
+                    field,
+                    request
+                );
+            }
+        }
+    }
+
+    fn broadcast_values(body: Value) -> (Vec<u64>, Vec<Value>) {
+        match serde_json::from_value(body).unwrap() {
+            Request::Broadcast { values, .. } => values.into_parts(),
+            request => panic!("not a broadcast: {:?}", request),
+        }
+    }
+
+    #[test]
+    fn broadcast_carries_one_value_or_several() {
+        assert_eq!(
+            broadcast_values(json!({"type": "broadcast", "message": 7})),
+            (vec![7], vec![])
+        );
+        assert_eq!(
+            broadcast_values(json!({"type": "broadcast", "messages": [7, 8]})),
+            (vec![7, 8], vec![])
+        );
+        // anything that isn't a u64 is a blob
+        assert_eq!(
+            broadcast_values(json!({"type": "broadcast", "messages": [7, "a"]})),
+            (vec![7], vec![json!("a")])
+        );
+        assert_eq!(
+            broadcast_values(json!({"type": "broadcast", "message": {"a": 1}})),
+            (vec![], vec![json!({"a": 1})])
+        );
+    }
+
+    fn topology_form(topology: Value) -> TopologyForm {
+        match serde_json::from_value(json!({"type": "topology", "topology": topology})).unwrap() {
+            Request::Topology { topology } => topology,
+            request => panic!("not a topology: {:?}", request),
+        }
+    }
+
+    fn nodes(nodes: &[&str]) -> Vec<String> {
+        nodes.iter().map(|node| node.to_string()).collect()
+    }
+
+    #[test]
+    fn topology_comes_as_a_list_or_a_matrix() {
+        let nodes = nodes(&["n1", "n2", "n3"]);
+        let list = topology_form(json!({"n1": ["n2"], "n2": ["n1", "n3"], "n3": ["n2"]}));
+        let matrix = topology_form(json!([[0, 1, 0], [1, 0, 1], [0, 1, 0]]));
+        assert!(matches!(list, TopologyForm::List(_)));
+        assert!(matches!(matrix, TopologyForm::Matrix(_)));
+        assert!(matrix.is_symmetric());
+        assert_eq!(matrix.into_list(&nodes), list.into_list(&nodes));
+    }
+
+    #[test]
+    fn matrix_must_have_a_row_and_column_per_node() {
+        let nodes = nodes(&["n1", "n2"]);
+        assert!(topology_form(json!([[0, 1]])).into_list(&nodes).is_none());
+        assert!(topology_form(json!([[0, 1], [1]]))
+            .into_list(&nodes)
+            .is_none());
+        assert!(!topology_form(json!([[0, 1], [0, 0]])).is_symmetric());
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+use std::time::Duration;
+
+// latency of one kind of db call. `txn` is the part spent in the redb transaction, the rest
+// is the handoff to the writer thread or the blocking pool
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpStats {
+    pub count: u64,
+    pub total: Duration,
+    pub txn: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl OpStats {
+    #[cfg(feature = "persistence")]
+    pub(crate) fn record(&mut self, elapsed: Duration, txn: Duration) {
+        self.min = if self.count == 0 {
+            elapsed
+        } else {
+            self.min.min(elapsed)
+        };
+        self.max = self.max.max(elapsed);
+        self.count += 1;
+        self.total += elapsed;
+        self.txn += txn;
+    }
+
+    pub fn avg(&self) -> Duration {
+        average(self.total, self.count)
+    }
+
+    pub fn avg_txn(&self) -> Duration {
+        average(self.txn, self.count)
+    }
+}
+
+fn average(total: Duration, count: u64) -> Duration {
+    Duration::from_nanos((total.as_nanos() / u128::from(count.max(1))) as u64)
+}
+
+impl fmt::Display for OpStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} calls, avg {:?} (txn {:?}), min {:?}, max {:?}",
+            self.count,
+            self.avg(),
+            self.avg_txn(),
+            self.min,
+            self.max
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DbStats {
+    pub set_broadcast_id: OpStats,
+    pub seen_broadcast_values: OpStats,
+}
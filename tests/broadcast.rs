@@ -0,0 +1,219 @@
+#![cfg(feature = "persistence")]
+
+mod harness;
+
+use harness::TestNode;
+use serde_json::json;
+use std::time::Duration;
+
+#[test]
+fn broadcast_is_stored_forwarded_and_read() {
+    let mut node = TestNode::start(&[]);
+    assert_eq!(node.init("n1", &["n1", "n2"])["type"], "init_ok");
+    let reply = node.request(
+        "c1",
+        json!({"type": "topology", "topology": {"n1": ["n2"], "n2": ["n1"]}}),
+    );
+    assert_eq!(reply["type"], "topology_ok");
+
+    let reply = node.request("c1", json!({"type": "broadcast", "message": 5}));
+    assert_eq!(reply["type"], "broadcast_ok");
+
+    let forwarded = node.next_to("n2", "batch_broadcast").unwrap();
+    assert_eq!(forwarded["body"]["messages"], json!([5]));
+    node.reply(
+        &forwarded,
+        json!({"type": "batch_broadcast_ok", "messages": [5]}),
+    );
+
+    let reply = node.request("c1", json!({"type": "read"}));
+    assert_eq!(reply["messages"], json!([5]));
+}
+
+// a node n1 connected to n2 only, with the given settings
+fn pair(envs: &[(&str, &str)]) -> TestNode {
+    let mut node = TestNode::start(envs);
+    node.init("n1", &["n1", "n2"]);
+    node.request(
+        "c1",
+        json!({"type": "topology", "topology": {"n1": ["n2"], "n2": ["n1"]}}),
+    );
+    node
+}
+
+#[test]
+fn unacked_value_stays_pending_and_is_retried() {
+    let mut node = pair(&[("GOSSIP_RETRY_INTERVAL_MS", "100")]);
+    node.request("c1", json!({"type": "broadcast", "message": 5}));
+
+    // the first send goes unanswered, as if the ack got lost
+    let first = node.next_to("n2", "batch_broadcast").unwrap();
+    assert_eq!(first["body"]["messages"], json!([5]));
+    let state = node.request("c1", json!({"type": "dump_state"}));
+    assert_eq!(state["memory"]["pending"]["n2"], json!([5]));
+
+    let retry = node.next_to("n2", "batch_broadcast").unwrap();
+    assert_eq!(retry["body"]["messages"], json!([5]));
+    node.reply(
+        &retry,
+        json!({"type": "batch_broadcast_ok", "messages": [5]}),
+    );
+
+    node.poll("c1", json!({"type": "dump_state"}), |state| {
+        state["memory"]["pending"]["n2"] == json!([])
+    });
+}
+
+#[test]
+fn single_node_stores_and_acks_without_forwarding() {
+    let mut node = TestNode::start(&[]);
+    node.init("n1", &["n1"]);
+    node.request("c1", json!({"type": "topology", "topology": {"n1": []}}));
+
+    for message in [3, 1, 2] {
+        let reply = node.request("c1", json!({"type": "broadcast", "message": message}));
+        assert_eq!(reply["type"], "broadcast_ok");
+    }
+    let reply = node.request("c1", json!({"type": "read"}));
+    assert_eq!(reply["messages"], json!([1, 2, 3]));
+    // only the replies to c1 went out
+    assert!(node.drain(|msg| msg["dest"] != "c1").is_empty());
+}
+
+// with two sender tasks and nobody answering, only two batches of the fanout are in flight
+#[test]
+fn large_fanout_is_sent_by_a_bounded_pool() {
+    let peers: Vec<String> = (2..10).map(|i| format!("n{}", i)).collect();
+    let mut nodes = vec!["n1"];
+    nodes.extend(peers.iter().map(String::as_str));
+    let mut node = TestNode::start(&[
+        ("GOSSIP_SENDERS", "2"),
+        ("GOSSIP_MAX_BATCH_SIZE", "1"),
+        ("GOSSIP_RETRY_INTERVAL_MS", "60000"),
+    ]);
+    node.init("n1", &nodes);
+    node.request("c1", json!({"type": "topology", "topology": {"n1": peers}}));
+    node.request("c1", json!({"type": "broadcast", "message": 1}));
+
+    let is_batch = |msg: &serde_json::Value| msg["body"]["type"] == "batch_broadcast";
+    std::thread::sleep(Duration::from_millis(300));
+    let in_flight = node.drain(is_batch);
+    assert_eq!(in_flight.len(), 2);
+
+    // every ack frees a sender for the next peer
+    node.reply(&in_flight[0], json!({"type": "batch_broadcast_ok"}));
+    let next = node.recv(is_batch, harness::TIMEOUT).unwrap();
+    assert!(!in_flight.iter().any(|msg| msg["dest"] == next["dest"]));
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(node.drain(is_batch).is_empty());
+}
+
+#[test]
+fn blobs_are_read_back_after_a_restart() {
+    let mut node = TestNode::start(&[]);
+    node.init("n1", &["n1"]);
+    node.request("c1", json!({"type": "broadcast", "message": {"a": 1}}));
+    node.request(
+        "c1",
+        json!({"type": "broadcast", "messages": [2, {"a": 1}, "b"]}),
+    );
+    let messages = node.request("c1", json!({"type": "read"}))["messages"].clone();
+    assert_eq!(messages.as_array().unwrap().len(), 3);
+    assert_eq!(messages[0], 2);
+
+    node.restart();
+    node.init("n1", &["n1"]);
+    assert_eq!(
+        node.request("c1", json!({"type": "read"}))["messages"],
+        messages
+    );
+}
+
+#[test]
+fn full_batch_is_sent_without_waiting_for_the_timer() {
+    let mut node = pair(&[
+        ("GOSSIP_MAX_BATCH_SIZE", "3"),
+        ("GOSSIP_MAX_BATCH_DELAY_MS", "60000"),
+    ]);
+    for message in [1, 2] {
+        node.request("c1", json!({"type": "broadcast", "message": message}));
+    }
+    let early = node.next_to_within("n2", "batch_broadcast", Duration::from_millis(300));
+    assert!(early.is_none());
+
+    node.request("c1", json!({"type": "broadcast", "message": 3}));
+    let batch = node
+        .next_to_within("n2", "batch_broadcast", Duration::from_secs(1))
+        .unwrap();
+    // the outbox doesn't keep the values in order
+    let mut messages: Vec<u64> = serde_json::from_value(batch["body"]["messages"].clone()).unwrap();
+    messages.sort();
+    assert_eq!(messages, vec![1, 2, 3]);
+}
+
+// n2 reports as many values as n1 has, so n1 logs convergence after the window
+#[test]
+fn convergence_is_checked_with_health_counts() {
+    let mut node = pair(&[("GOSSIP_CONVERGENCE_WINDOW_MS", "200")]);
+    node.request("c1", json!({"type": "broadcast", "message": 5}));
+
+    let deadline = std::time::Instant::now() + harness::TIMEOUT;
+    while !node.has_logged("converged at 1 values on 2 nodes") {
+        assert!(std::time::Instant::now() < deadline, "never converged");
+        if let Some(health) = node.next_to_within("n2", "health", Duration::from_millis(100)) {
+            node.reply(&health, json!({"type": "health_ok", "seen": 1}));
+        }
+    }
+    assert!(node.drain(|msg| msg["body"]["type"] == "read").is_empty());
+}
+
+#[test]
+fn reset_forgets_every_value() {
+    let mut node = TestNode::start(&[]);
+    node.init("n1", &["n1"]);
+    node.request(
+        "c1",
+        json!({"type": "broadcast", "messages": [1, 2, {"a": 1}]}),
+    );
+    assert_eq!(
+        node.request("c1", json!({"type": "reset"}))["type"],
+        "reset_ok"
+    );
+    assert_eq!(
+        node.request("c1", json!({"type": "read"}))["messages"],
+        json!([])
+    );
+
+    node.request("c1", json!({"type": "broadcast", "message": 2}));
+    assert_eq!(
+        node.request("c1", json!({"type": "read"}))["messages"],
+        json!([2])
+    );
+}
+
+// the ack answers the batch that was sent, but only the values it names are cleared
+#[test]
+fn batched_ack_clears_the_values_it_names() {
+    let mut node = pair(&[("GOSSIP_RETRY_INTERVAL_MS", "60000")]);
+    node.request("c1", json!({"type": "broadcast", "messages": [1, 2, 3]}));
+    let batch = node.next_to("n2", "batch_broadcast").unwrap();
+    node.poll("c1", json!({"type": "dump_state"}), |state| {
+        state["memory"]["pending"]["n2"] == json!([1, 2, 3])
+    });
+
+    node.reply(
+        &batch,
+        json!({"type": "batch_broadcast_ok", "messages": [1, 3]}),
+    );
+    node.poll("c1", json!({"type": "dump_state"}), |state| {
+        state["memory"]["pending"]["n2"] == json!([2])
+    });
+}
+
+#[test]
+fn batch_is_acked_with_its_values() {
+    let mut node = pair(&[]);
+    let reply = node.request("n2", json!({"type": "batch_broadcast", "messages": [4, 5]}));
+    assert_eq!(reply["type"], "batch_broadcast_ok");
+    assert_eq!(reply["messages"], json!([4, 5]));
+}
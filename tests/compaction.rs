@@ -0,0 +1,22 @@
+#![cfg(feature = "persistence")]
+
+mod harness;
+
+use harness::TestNode;
+use serde_json::json;
+use std::time::Duration;
+
+#[test]
+fn compaction_is_skipped_while_writes_go_on() {
+    let mut node = TestNode::start(&[("GOSSIP_COMPACT_IDLE_MS", "1500")]);
+    node.init("n1", &["n1"]);
+    for message in 0..30 {
+        node.request("c1", json!({"type": "broadcast", "message": message}));
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    // matches both "Compacted db" and "Db compaction had nothing to reclaim"
+    assert!(!node.has_logged("ompact"));
+
+    // idle from here on
+    assert!(node.logged("ompact"));
+}
@@ -0,0 +1,17 @@
+#![cfg(feature = "persistence")]
+
+mod harness;
+
+use harness::TestNode;
+use serde_json::json;
+
+#[test]
+fn resent_add_is_counted_once() {
+    let mut node = TestNode::start(&[("GOSSIP_WORKLOAD", "g-counter")]);
+    node.init("n1", &["n1"]);
+    let add = json!({"type": "add", "delta": 3, "msg_id": 7});
+    assert_eq!(node.request("c1", add.clone())["type"], "add_ok");
+    assert_eq!(node.request("c1", add)["type"], "add_ok");
+
+    assert_eq!(node.request("c1", json!({"type": "read"}))["value"], 3);
+}
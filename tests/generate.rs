@@ -0,0 +1,55 @@
+#![cfg(feature = "persistence")]
+
+mod harness;
+
+use harness::TestNode;
+use serde_json::json;
+use std::collections::HashSet;
+
+fn generate(node: &mut TestNode) -> serde_json::Value {
+    node.request("c1", json!({"type": "generate"}))
+}
+
+fn counter(id: &serde_json::Value) -> u64 {
+    let id = id["id"].as_str().unwrap();
+    id.strip_prefix("n1-").unwrap().parse().unwrap()
+}
+
+#[test]
+fn node_counter_continues_past_a_restart() {
+    let mut node = TestNode::start(&[("GOSSIP_ID_SCHEME", "node-counter")]);
+    node.init("n1", &["n1"]);
+    let before: Vec<u64> = (0..10).map(|_| counter(&generate(&mut node))).collect();
+
+    node.restart();
+    node.init("n1", &["n1"]);
+    let after: Vec<u64> = (0..10).map(|_| counter(&generate(&mut node))).collect();
+    let last = before.iter().max().unwrap();
+    assert!(
+        after.iter().all(|id| id > last),
+        "{:?} after {:?}",
+        after,
+        before
+    );
+    let unique: HashSet<u64> = before.iter().chain(&after).copied().collect();
+    assert_eq!(unique.len(), 20);
+}
+
+#[test]
+fn node_counter_ids_are_refused_after_the_db_was_recreated() {
+    let mut node = TestNode::start(&[("GOSSIP_ID_SCHEME", "node-counter")]);
+    std::fs::write(node.dir().join("n1.redb"), b"not a redb file").unwrap();
+    node.init("n1", &["n1"]);
+    assert_eq!(generate(&mut node)["type"], "error");
+    assert!(node.logged("node-counter ids could repeat"));
+}
+
+#[test]
+fn seeded_uuids_still_differ_after_a_restart() {
+    let mut node = TestNode::start(&[("GOSSIP_SEED", "42")]);
+    node.init("n1", &["n1"]);
+    let before = generate(&mut node)["id"].clone();
+    node.restart();
+    node.init("n1", &["n1"]);
+    assert_ne!(generate(&mut node)["id"], before);
+}
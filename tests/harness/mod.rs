@@ -0,0 +1,271 @@
+// drives the node binary the way maelstrom does, one JSON message per line on stdin and
+// stdout, so tests can send requests, capture the replies and play the node's peers. Each
+// node gets its own db directory, removed again when it is dropped.
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how long a test waits for a reply or a log line before failing
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+pub struct TestNode {
+    child: Child,
+    stdin: ChildStdin,
+    out: Receiver<Value>,
+    // messages read while waiting for a different one
+    backlog: VecDeque<Value>,
+    logs: Arc<Mutex<Vec<String>>>,
+    dir: PathBuf,
+    envs: Vec<(String, String)>,
+    args: Vec<String>,
+    // dest of the messages sent to the node, the runtime doesn't check it
+    node_id: String,
+    next_msg_id: u64,
+    // false for a node replaced by `restart`, its successor keeps using the directory
+    owns_dir: bool,
+}
+
+impl TestNode {
+    // a node with a fresh db directory, envs are GOSSIP_* settings on top of the defaults
+    pub fn start(envs: &[(&str, &str)]) -> Self {
+        Self::start_with_args(envs, &[])
+    }
+
+    pub fn start_with_args(envs: &[(&str, &str)], args: &[&str]) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "gossip-test-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let envs = envs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        Self::spawn(dir, envs, args)
+    }
+
+    fn spawn(dir: PathBuf, envs: Vec<(String, String)>, args: Vec<String>) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_flyio-gossip-glomers-challenge"))
+            .args(&args)
+            .env("GOSSIP_DB_DIR", &dir)
+            .env("RUST_LOG", "debug")
+            .envs(envs.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let (tx, out) = mpsc::channel();
+        let stdout = child.stdout.take().unwrap();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { return };
+                let msg = serde_json::from_str(&line)
+                    .unwrap_or_else(|e| panic!("node wrote a non-JSON line {:?}: {}", line, e));
+                if tx.send(msg).is_err() {
+                    return;
+                }
+            }
+        });
+        let logs = Arc::new(Mutex::new(vec![]));
+        let stderr = child.stderr.take().unwrap();
+        {
+            let logs = logs.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(stderr).lines() {
+                    let Ok(line) = line else { return };
+                    logs.lock().unwrap().push(line);
+                }
+            });
+        }
+
+        Self {
+            stdin: child.stdin.take().unwrap(),
+            child,
+            out,
+            backlog: VecDeque::new(),
+            logs,
+            dir,
+            envs,
+            args,
+            node_id: "n1".to_string(),
+            next_msg_id: 1,
+            owns_dir: true,
+        }
+    }
+
+    // kills the node and starts it again on the same db directory, like a crash and restart
+    pub fn restart(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let mut next = Self::spawn(self.dir.clone(), self.envs.clone(), self.args.clone());
+        next.node_id = self.node_id.clone();
+        std::mem::replace(self, next).owns_dir = false;
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // sends init from c0 and waits for init_ok
+    pub fn init(&mut self, node_id: &str, node_ids: &[&str]) -> Value {
+        self.node_id = node_id.to_string();
+        self.request(
+            "c0",
+            json!({"type": "init", "node_id": node_id, "node_ids": node_ids}),
+        )
+    }
+
+    // sends body from src with the next msg_id unless it has one, returns the msg_id
+    pub fn send(&mut self, src: &str, mut body: Value) -> u64 {
+        let msg_id = match body.get("msg_id").and_then(Value::as_u64) {
+            Some(msg_id) => msg_id,
+            None => {
+                let msg_id = self.next_msg_id;
+                self.next_msg_id += 1;
+                body["msg_id"] = msg_id.into();
+                msg_id
+            }
+        };
+        self.send_raw(json!({"src": src, "dest": self.node_id, "body": body}));
+        msg_id
+    }
+
+    pub fn send_raw(&mut self, msg: Value) {
+        writeln!(self.stdin, "{}", msg).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    // sends body from src and returns the body of the reply to it
+    pub fn request(&mut self, src: &str, body: Value) -> Value {
+        let msg_id = self.send(src, body);
+        self.reply_to(src, msg_id)
+            .unwrap_or_else(|| panic!("no reply to msg {} from {}", msg_id, src))
+    }
+
+    // repeats a request until its reply satisfies done, for state that settles in the
+    // background. Returns that reply, panics with the last one after TIMEOUT.
+    pub fn poll(&mut self, src: &str, body: Value, done: impl Fn(&Value) -> bool) -> Value {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let reply = self.request(src, body.clone());
+            if done(&reply) {
+                return reply;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "still {} after {:?}",
+                reply,
+                TIMEOUT
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    // body of the reply to msg_id sent by src, None if it doesn't come within TIMEOUT
+    pub fn reply_to(&mut self, src: &str, msg_id: u64) -> Option<Value> {
+        let src = src.to_string();
+        self.recv(
+            |msg| msg["dest"] == src.as_str() && msg["body"]["in_reply_to"] == msg_id,
+            TIMEOUT,
+        )
+        .map(|msg| msg["body"].clone())
+    }
+
+    // next message the node sends to dest of the given type
+    pub fn next_to(&mut self, dest: &str, typ: &str) -> Option<Value> {
+        self.next_to_within(dest, typ, TIMEOUT)
+    }
+
+    pub fn next_to_within(&mut self, dest: &str, typ: &str, timeout: Duration) -> Option<Value> {
+        let (dest, typ) = (dest.to_string(), typ.to_string());
+        self.recv(
+            |msg| msg["dest"] == dest.as_str() && msg["body"]["type"] == typ.as_str(),
+            timeout,
+        )
+    }
+
+    // first message, buffered or new, that matches. The ones skipped stay buffered.
+    pub fn recv(&mut self, matches: impl Fn(&Value) -> bool, timeout: Duration) -> Option<Value> {
+        if let Some(i) = self.backlog.iter().position(&matches) {
+            return self.backlog.remove(i);
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.out.recv_timeout(left) {
+                Ok(msg) if matches(&msg) => return Some(msg),
+                Ok(msg) => self.backlog.push_back(msg),
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    // every message sent so far that matches, without waiting for more
+    pub fn drain(&mut self, matches: impl Fn(&Value) -> bool) -> Vec<Value> {
+        while let Ok(msg) = self.out.try_recv() {
+            self.backlog.push_back(msg);
+        }
+        let (matched, rest): (Vec<Value>, Vec<Value>) = std::mem::take(&mut self.backlog)
+            .into_iter()
+            .partition(|msg| matches(msg));
+        self.backlog = rest.into();
+        matched
+    }
+
+    // answers a message the node sent, as the peer it was sent to
+    pub fn reply(&mut self, msg: &Value, mut body: Value) {
+        body["in_reply_to"] = msg["body"]["msg_id"].clone();
+        self.send_raw(json!({"src": msg["dest"], "dest": msg["src"], "body": body}));
+    }
+
+    // waits until the node has logged a line containing needle
+    pub fn logged(&self, needle: &str) -> bool {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            if self
+                .logs
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|line| line.contains(needle))
+            {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    // whether a line containing needle was logged so far, without waiting
+    pub fn has_logged(&self, needle: &str) -> bool {
+        self.logs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|line| line.contains(needle))
+    }
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if self.owns_dir {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+#![cfg(feature = "persistence")]
+
+mod harness;
+
+use harness::TestNode;
+use serde_json::json;
+use std::time::Duration;
+
+// a broadcast racing init is neither an error nor fatal, it is answered once init is done
+#[test]
+fn broadcast_before_init_waits_for_init() {
+    let mut node = TestNode::start(&[]);
+    let msg_id = node.send("c1", json!({"type": "broadcast", "message": 1}));
+    assert!(node
+        .recv(
+            |msg| msg["body"]["in_reply_to"] == msg_id,
+            Duration::from_millis(300)
+        )
+        .is_none());
+    assert_eq!(
+        node.request("c2", json!({"type": "health"}))["initialized"],
+        false
+    );
+
+    node.init("n1", &["n1"]);
+    assert_eq!(node.reply_to("c1", msg_id).unwrap()["type"], "broadcast_ok");
+}
+
+#[test]
+fn repeated_init_keeps_one_addressbook_and_db() {
+    let mut node = TestNode::start(&[]);
+    node.init("n1", &["n1", "n2", "n3"]);
+    node.request("c1", json!({"type": "broadcast", "message": 1}));
+    let before = node.request("c1", json!({"type": "dump_state"}));
+
+    // acked again, but neither the peers nor the stored values change
+    assert_eq!(node.init("n1", &["n1", "n4"])["type"], "init_ok");
+    assert!(node.logged("Ignoring repeated init"));
+    let after = node.request("c1", json!({"type": "dump_state"}));
+    assert_eq!(
+        after["memory"]["addressbook"],
+        before["memory"]["addressbook"]
+    );
+    assert_eq!(after["memory"]["addressbook"], json!({"n2": [], "n3": []}));
+    assert_eq!(
+        node.request("c1", json!({"type": "read"}))["messages"],
+        json!([1])
+    );
+
+    let files: Vec<_> = std::fs::read_dir(node.dir()).unwrap().collect();
+    assert_eq!(files.len(), 1);
+}
+
+#[test]
+fn broadcast_before_init_is_applied() {
+    let mut node = TestNode::start(&[]);
+    node.send("c1", json!({"type": "broadcast", "message": 7}));
+    node.init("n1", &["n1"]);
+    node.poll("c2", json!({"type": "read"}), |reply| {
+        reply["messages"] == json!([7])
+    });
+}
+
+// answered with a maelstrom error instead of stopping the node
+#[test]
+fn init_without_a_node_id_is_rejected() {
+    let mut node = TestNode::start(&[]);
+    let reply = node.init("", &["n1"]);
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 12);
+    assert_eq!(
+        node.request("c1", json!({"type": "health"}))["type"],
+        "health_ok"
+    );
+}
+
+// held messages are answered with a retriable error instead of waiting forever
+#[test]
+fn messages_held_for_a_failed_init_are_turned_away() {
+    let mut node = TestNode::start(&[("GOSSIP_KEEP_CORRUPT_DB", "1")]);
+    std::fs::write(node.dir().join("n1.redb"), b"not a redb file").unwrap();
+    let msg_id = node.send("c1", json!({"type": "broadcast", "message": 1}));
+
+    assert_eq!(node.init("n1", &["n1"])["code"], 11);
+    let reply = node.reply_to("c1", msg_id).unwrap();
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 11);
+    let reply = node.request("c2", json!({"type": "read"}));
+    assert_eq!(reply["code"], 11);
+}
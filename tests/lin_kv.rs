@@ -0,0 +1,24 @@
+#![cfg(feature = "persistence")]
+
+mod harness;
+
+use harness::TestNode;
+use serde_json::json;
+
+#[test]
+fn cas_errors_use_the_maelstrom_codes() {
+    let mut node = TestNode::start(&[("GOSSIP_WORKLOAD", "lin-kv")]);
+    node.init("n1", &["n1"]);
+
+    let reply = node.request("c1", json!({"type": "read", "key": 1}));
+    assert_eq!(reply["code"], 20);
+    let reply = node.request("c1", json!({"type": "write", "key": 1, "value": 10}));
+    assert_eq!(reply["type"], "write_ok");
+
+    let reply = node.request("c1", json!({"type": "cas", "key": 1, "from": 10, "to": 11}));
+    assert_eq!(reply["type"], "cas_ok");
+    let reply = node.request("c1", json!({"type": "cas", "key": 1, "from": 10, "to": 12}));
+    assert_eq!(reply["code"], 22);
+    let reply = node.request("c1", json!({"type": "read", "key": 1}));
+    assert_eq!(reply["value"], 11);
+}
@@ -0,0 +1,23 @@
+#![cfg(not(feature = "persistence"))]
+
+mod harness;
+
+use harness::TestNode;
+use serde_json::json;
+
+// without storage a node still starts and answers what needs none, the rest is refused
+#[test]
+fn storage_less_node_refuses_only_what_needs_storage() {
+    let mut node = TestNode::start(&[]);
+    assert_eq!(node.init("n1", &["n1"])["type"], "init_ok");
+    let reply = node.request("c1", json!({"type": "echo", "echo": "hi"}));
+    assert_eq!(reply["echo"], "hi");
+    assert_eq!(
+        node.request("c1", json!({"type": "generate"}))["type"],
+        "generate_ok"
+    );
+
+    let reply = node.request("c1", json!({"type": "broadcast", "message": 1}));
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 10);
+}
@@ -0,0 +1,24 @@
+mod harness;
+
+use harness::TestNode;
+use serde_json::json;
+
+#[test]
+fn misspelled_field_is_logged() {
+    let mut node = TestNode::start(&[]);
+    node.init("n1", &["n1"]);
+    let reply = node.request("c1", json!({"type": "read", "limt": 10}));
+    assert_eq!(reply["type"], "read_ok");
+    assert!(node.logged("unexpected fields [\"limt\"]"));
+}
+
+#[test]
+fn misspelled_field_is_rejected_when_strict() {
+    let mut node = TestNode::start(&[("GOSSIP_STRICT", "1")]);
+    node.init("n1", &["n1"]);
+    let reply = node.request("c1", json!({"type": "read", "limt": 10}));
+    assert_eq!(reply["type"], "error");
+    // known fields still pass
+    let reply = node.request("c1", json!({"type": "read", "limit": 10}));
+    assert_eq!(reply["type"], "read_ok");
+}
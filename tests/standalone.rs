@@ -0,0 +1,31 @@
+#![cfg(feature = "persistence")]
+
+mod harness;
+
+use harness::TestNode;
+use serde_json::json;
+
+// no init ever comes, the node runs under the id it was started with
+#[test]
+fn ids_use_the_override_node_id() {
+    let mut node = TestNode::start_with_args(
+        &[("GOSSIP_ID_SCHEME", "node-counter")],
+        &["--node-id", "n7"],
+    );
+    let reply = node.request("c1", json!({"type": "generate"}));
+    assert!(
+        reply["id"].as_str().unwrap().starts_with("n7-"),
+        "{}",
+        reply
+    );
+}
+
+#[test]
+fn counter_adds_under_the_override_node_id() {
+    let mut node =
+        TestNode::start_with_args(&[("GOSSIP_WORKLOAD", "g-counter")], &["--node-id=n7"]);
+    node.request("c1", json!({"type": "add", "delta": 2}));
+    let state = node.request("c1", json!({"type": "dump_state"}));
+    assert_eq!(state["state"]["counters"]["n7"], 2, "{}", state);
+    assert_eq!(node.request("c1", json!({"type": "read"}))["value"], 2);
+}
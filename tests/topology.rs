@@ -0,0 +1,18 @@
+mod harness;
+
+use harness::TestNode;
+use serde_json::json;
+
+#[test]
+fn disconnected_topology_is_applied_with_a_warning() {
+    let mut node = TestNode::start(&[]);
+    node.init("n1", &["n1", "n2", "n3", "n4"]);
+    let reply = node.request(
+        "c1",
+        json!({"type": "topology", "topology": {
+            "n1": ["n2"], "n2": ["n1"], "n3": ["n4"], "n4": ["n3"],
+        }}),
+    );
+    assert_eq!(reply["type"], "topology_ok");
+    assert!(node.logged("Topology is not connected, {\"n3\", \"n4\"}"));
+}